@@ -0,0 +1,54 @@
+use crate::{
+    misc::{privileges, Privileges, DEFAULT_ESCALATION},
+    network::format::Format,
+    report::Reporter,
+};
+
+/*
+ * How to run
+ * The settings of a run (--dry-run, --escalate, --format, --json, the network
+ * namespace) are passed around in a Context rather than kept in globals, so
+ * that a program embedding tulip_net can work on several networks at once,
+ * each with its own. Operations on a network use a copy in the network's
+ * namespace (see network::prepare). Clones share their Reporter.
+ */
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// Print the commands start and stop would run, and the files they would
+    /// stage, instead of running and writing them
+    pub dry_run: bool,
+    /// Program to get privileges with when we don't have them (see misc.rs)
+    pub escalation: String,
+    /// The privileges we have
+    pub privileges: Privileges,
+    /// Network namespace the networking commands run in
+    pub netns: Option<String>,
+    /// Format of the network and phonebook files whose extension doesn't say
+    pub format: Option<Format>,
+    /// Where messages and results go
+    pub report: Reporter,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            dry_run: false,
+            escalation: String::from(DEFAULT_ESCALATION),
+            privileges: privileges(),
+            netns: None,
+            format: None,
+            report: Reporter::default(),
+        }
+    }
+}
+
+impl Context {
+    /// This Context, in the network namespace `netns`
+    pub fn with_netns(&self, netns: Option<String>) -> Context {
+        Context {
+            netns,
+            ..self.clone()
+        }
+    }
+}
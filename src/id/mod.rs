@@ -0,0 +1,177 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self},
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::{context::Context, misc::create_private_file, schema};
+pub mod rotation;
+
+#[derive(Debug)]
+pub enum IdError {
+    BadKey(String),
+    BadRotation(String),
+    FileIO(std::io::Error),
+    FromUtf8Error(std::string::FromUtf8Error),
+    KeyFileExists(String),
+    PipeError(String),
+    Serde(serde_json::Error),
+}
+
+impl std::fmt::Display for IdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdError::BadKey(msg) => write!(f, "{msg}"),
+            IdError::BadRotation(msg) => write!(f, "bad rotation request: {msg}"),
+            IdError::FileIO(e) => write!(f, "file error: {e}"),
+            IdError::FromUtf8Error(e) => write!(f, "utf-8 error: {e}"),
+            IdError::KeyFileExists(msg) => write!(f, "{msg}"),
+            IdError::PipeError(msg) => write!(f, "pipe error: {msg}"),
+            IdError::Serde(e) => write!(f, "json error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for IdError {}
+
+impl From<std::io::Error> for IdError {
+    fn from(e: std::io::Error) -> Self {
+        IdError::FileIO(e)
+    }
+}
+
+impl From<serde_json::Error> for IdError {
+    fn from(e: serde_json::Error) -> Self {
+        IdError::Serde(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for IdError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        IdError::FromUtf8Error(e)
+    }
+}
+
+fn genkey() -> Result<Vec<u8>, IdError> {
+    let mut priv_key = Command::new("wg").arg("genkey").output()?.stdout;
+    priv_key.pop();
+    Ok(priv_key)
+}
+
+fn priv_key_to_pub_key(input: &[u8]) -> Result<String, IdError> {
+    let mut pub_key_cmd = Command::new("wg")
+        .arg("pubkey")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    pub_key_cmd
+        .stdin
+        .take()
+        .ok_or(IdError::PipeError(String::from("no")))?
+        .write_all(input)?;
+    let mut res = String::from_utf8(pub_key_cmd.wait_with_output()?.stdout)?;
+    res.pop();
+    Ok(res)
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct PrivId {
+    /// Your name in the phonebook
+    pub name: String,
+    /// Your WireGuard private key. Never share it
+    #[schemars(schema_with = "schema::wg_key")]
+    pub private_key: String,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct PubId {
+    /// Your name in the phonebook
+    pub name: String,
+    /// Your WireGuard public key, for the network admin
+    #[schemars(schema_with = "schema::wg_key")]
+    pub public_key: String,
+}
+
+pub fn gen_id(name: &str) -> Result<(PrivId, PubId), IdError> {
+    let priv_key = genkey()?;
+    let pub_key = priv_key_to_pub_key(&priv_key)?;
+    let priv_key = String::from_utf8(priv_key)?;
+    let pub_id_struct = PubId {
+        name: name.to_string(),
+        public_key: pub_key,
+    };
+    let priv_id_struct = PrivId {
+        name: name.to_string(),
+        private_key: priv_key,
+    };
+    Ok((priv_id_struct, pub_id_struct))
+}
+
+pub fn gen_id_files(ctx: &Context, name: String) -> Result<PubId, IdError> {
+    let (priv_id_struct, pub_id_struct) = gen_id(&name)?;
+    let pub_id_json = serde_json::to_string_pretty(&pub_id_struct)?;
+    let priv_id_json = serde_json::to_string_pretty(&priv_id_struct)?;
+    let pub_id_filepath = format!("{}_public_id.json", &name);
+    let priv_id_filepath = format!("{}_private_id.json", &name);
+    if Path::new(&pub_id_filepath).exists() || Path::new(&priv_id_filepath).exists() {
+        return Err(IdError::KeyFileExists(format!(
+            "i won't overwrite {} and/or {}",
+            &pub_id_filepath, &priv_id_filepath
+        )));
+    }
+    let mut pub_id_file = create_private_file(&pub_id_filepath)?;
+    let mut priv_id_file = create_private_file(&priv_id_filepath)?;
+    ctx.report.say(&format!("writing {}", &pub_id_filepath));
+    ctx.report.written(&pub_id_filepath);
+    writeln!(pub_id_file, "{}", pub_id_json)?;
+    ctx.report.say(&format!("writing {}", &priv_id_filepath));
+    ctx.report.written(&priv_id_filepath);
+    writeln!(priv_id_file, "{}", priv_id_json)?;
+    Ok(pub_id_struct)
+}
+
+/// Generate a new keypair for `old`'s name and write it to
+/// `<name>_rotated_private_id.json`, along with
+/// `<name>_rotation_request.json` for the network admin. The old private ID is left untouched.
+pub fn rotate_id_files(
+    ctx: &Context,
+    old: &PrivId,
+    endpoint_public_keys: &[&str],
+) -> Result<(), IdError> {
+    let (new, _) = gen_id(&old.name)?;
+    let request = rotation::rotation_request(old, &new, endpoint_public_keys)?;
+    let priv_id_json = serde_json::to_string_pretty(&new)?;
+    let request_json = serde_json::to_string_pretty(&request)?;
+    let priv_id_filepath = format!("{}_rotated_private_id.json", &old.name);
+    let request_filepath = format!("{}_rotation_request.json", &old.name);
+    if Path::new(&priv_id_filepath).exists() || Path::new(&request_filepath).exists() {
+        return Err(IdError::KeyFileExists(format!(
+            "i won't overwrite {} and/or {}",
+            &priv_id_filepath, &request_filepath
+        )));
+    }
+    let mut priv_id_file = create_private_file(&priv_id_filepath)?;
+    let mut request_file = create_private_file(&request_filepath)?;
+    ctx.report.say(&format!("writing {}", &priv_id_filepath));
+    ctx.report.written(&priv_id_filepath);
+    writeln!(priv_id_file, "{}", priv_id_json)?;
+    ctx.report.say(&format!("writing {}", &request_filepath));
+    ctx.report.written(&request_filepath);
+    writeln!(request_file, "{}", request_json)?;
+    Ok(())
+}
+
+pub fn read_rotation_request_file(path: &str) -> Result<rotation::RotationRequest, IdError> {
+    let request_json = fs::read_to_string(path)?;
+    let res = serde_json::from_str(&request_json)?;
+    Ok(res)
+}
+
+pub fn read_id_file(path: &str) -> Result<PrivId, IdError> {
+    let priv_id_json = fs::read_to_string(path)?;
+    let res = serde_json::from_str(&priv_id_json)?;
+    Ok(res)
+}
@@ -0,0 +1,206 @@
+use super::{IdError, PrivId};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/*
+ * Key rotation requests
+ * A RotationRequest asks a Tulip network admin to replace a user's public key
+ * in the phonebook. For every PublicEndpoint of the network, it carries two
+ * proofs: an HMAC of the request keyed with the X25519 shared secret between
+ * the endpoint and the old key, and the same keyed with the new key.
+ * Only the holder of the old (resp. new) private key, or of the endpoint's
+ * private key, can compute them.
+ */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotationProof {
+    pub endpoint_public_key: String,
+    pub old_key_proof: String,
+    pub new_key_proof: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotationRequest {
+    pub name: String,
+    pub old_public_key: String,
+    pub new_public_key: String,
+    pub proofs: Vec<RotationProof>,
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], IdError> {
+    STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(IdError::BadKey(format!("{} is not a WireGuard key", key)))
+}
+
+pub fn public_key(private_key: &str) -> Result<String, IdError> {
+    let secret = StaticSecret::from(decode_key(private_key)?);
+    Ok(STANDARD.encode(PublicKey::from(&secret).as_bytes()))
+}
+
+fn message(name: &str, old_public_key: &str, new_public_key: &str) -> String {
+    format!("tulip key rotation\n{name}\n{old_public_key}\n{new_public_key}\n")
+}
+
+fn proof(private_key: &str, public_key: &str, message: &str) -> Result<String, IdError> {
+    let secret = StaticSecret::from(decode_key(private_key)?);
+    let shared = secret.diffie_hellman(&PublicKey::from(decode_key(public_key)?));
+    let mut mac = Hmac::<Sha256>::new_from_slice(shared.as_bytes()).expect("hmac key issue");
+    mac.update(message.as_bytes());
+    Ok(STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+fn verify(private_key: &str, public_key: &str, message: &str, proof: &str) -> bool {
+    let secret = match decode_key(private_key) {
+        Ok(key) => StaticSecret::from(key),
+        Err(_) => return false,
+    };
+    let peer = match decode_key(public_key) {
+        Ok(key) => PublicKey::from(key),
+        Err(_) => return false,
+    };
+    let proof = match STANDARD.decode(proof) {
+        Ok(proof) => proof,
+        Err(_) => return false,
+    };
+    let shared = secret.diffie_hellman(&peer);
+    let mut mac = Hmac::<Sha256>::new_from_slice(shared.as_bytes()).expect("hmac key issue");
+    mac.update(message.as_bytes());
+    mac.verify_slice(&proof).is_ok()
+}
+
+/// Build a request to swap `old`'s public key for `new`'s, with proofs for
+/// each of the network's PublicEndpoints.
+pub fn rotation_request(
+    old: &PrivId,
+    new: &PrivId,
+    endpoint_public_keys: &[&str],
+) -> Result<RotationRequest, IdError> {
+    let old_public_key = public_key(&old.private_key)?;
+    let new_public_key = public_key(&new.private_key)?;
+    let message = message(&old.name, &old_public_key, &new_public_key);
+    let proofs = endpoint_public_keys
+        .iter()
+        .map(|endpoint_public_key| {
+            Ok(RotationProof {
+                endpoint_public_key: endpoint_public_key.to_string(),
+                old_key_proof: proof(&old.private_key, endpoint_public_key, &message)?,
+                new_key_proof: proof(&new.private_key, endpoint_public_key, &message)?,
+            })
+        })
+        .collect::<Result<Vec<RotationProof>, IdError>>()?;
+    Ok(RotationRequest {
+        name: old.name.clone(),
+        old_public_key,
+        new_public_key,
+        proofs,
+    })
+}
+
+/// Check a request's proofs using the private ID of one of the network's
+/// PublicEndpoints.
+pub fn verify_rotation_request(
+    request: &RotationRequest,
+    endpoint: &PrivId,
+) -> Result<(), IdError> {
+    let endpoint_public_key = public_key(&endpoint.private_key)?;
+    let proof = request
+        .proofs
+        .iter()
+        .find(|proof| proof.endpoint_public_key == endpoint_public_key)
+        .ok_or(IdError::BadRotation(format!(
+            "the request has no proof for public key {}",
+            endpoint_public_key
+        )))?;
+    let message = message(
+        &request.name,
+        &request.old_public_key,
+        &request.new_public_key,
+    );
+    if !verify(
+        &endpoint.private_key,
+        &request.old_public_key,
+        &message,
+        &proof.old_key_proof,
+    ) {
+        return Err(IdError::BadRotation(String::from(
+            "the request doesn't prove possession of the old key",
+        )));
+    }
+    if !verify(
+        &endpoint.private_key,
+        &request.new_public_key,
+        &message,
+        &proof.new_key_proof,
+    ) {
+        return Err(IdError::BadRotation(String::from(
+            "the request doesn't prove possession of the new key",
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priv_id(name: &str, seed: u8) -> PrivId {
+        PrivId {
+            name: String::from(name),
+            private_key: STANDARD.encode([seed; 32]),
+        }
+    }
+
+    fn request(hub: &PrivId) -> RotationRequest {
+        let hub_public_key = public_key(&hub.private_key).unwrap();
+        rotation_request(
+            &priv_id("janie", 1),
+            &priv_id("janie", 2),
+            &[&hub_public_key],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_request_passes() {
+        let hub = priv_id("hub", 3);
+        assert!(verify_rotation_request(&request(&hub), &hub).is_ok());
+    }
+
+    #[test]
+    fn tampered_new_key_fails() {
+        let hub = priv_id("hub", 3);
+        let mut request = request(&hub);
+        request.new_public_key = public_key(&priv_id("eve", 4).private_key).unwrap();
+        assert!(matches!(
+            verify_rotation_request(&request, &hub),
+            Err(IdError::BadRotation(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_endpoint_fails() {
+        let request = request(&priv_id("hub", 3));
+        assert!(matches!(
+            verify_rotation_request(&request, &priv_id("other-hub", 5)),
+            Err(IdError::BadRotation(_))
+        ));
+    }
+
+    #[test]
+    fn swapped_proofs_fail() {
+        let hub = priv_id("hub", 3);
+        let mut request = request(&hub);
+        let proof = &mut request.proofs[0];
+        std::mem::swap(&mut proof.old_key_proof, &mut proof.new_key_proof);
+        assert!(matches!(
+            verify_rotation_request(&request, &hub),
+            Err(IdError::BadRotation(_))
+        ));
+    }
+}
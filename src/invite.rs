@@ -0,0 +1,433 @@
+use crate::{
+    context::Context,
+    id::{self, IdError},
+    misc::create_private_file,
+    network::{
+        self,
+        phonebook::{self, Phonebook},
+        revocation, Network, NetworkError, PrivateEndpoint, UserEndpoint,
+    },
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Write},
+    net::Ipv4Addr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/*
+ * Invite-based enrollment
+ * - The admin mints a single-use, expiring invite with `tulip invite create`.
+ *    Only a hash of its secret is kept, in invites.json.
+ * - The user runs `tulip join <token>`, which generates their ID and posts
+ *    their public key to the hub's /enroll endpoint (see serve.rs).
+ * - The hub allocates a free VPN IP, adds the user to phonebook.json and
+ *    answers with the user's tulip_network.json.
+ */
+
+const TOKEN_PREFIX: &str = "tulip-invite:";
+
+#[derive(Debug)]
+pub enum InviteError {
+    BadRequest(String),
+    BadToken(String),
+    Conflict(String),
+    FileIO(std::io::Error),
+    Id(IdError),
+    Network(NetworkError),
+    NoFreeIp(String),
+    Rejected(String),
+    Serde(serde_json::Error),
+    Ureq(Box<ureq::Error>),
+}
+
+impl std::fmt::Display for InviteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InviteError::BadRequest(msg) => write!(f, "bad request: {msg}"),
+            InviteError::BadToken(msg) => write!(f, "bad invite: {msg}"),
+            InviteError::Conflict(msg) => write!(f, "{msg}"),
+            InviteError::FileIO(e) => write!(f, "file error: {e}"),
+            InviteError::Id(e) => write!(f, "{e}"),
+            InviteError::Network(e) => write!(f, "{e}"),
+            InviteError::NoFreeIp(msg) => write!(f, "{msg}"),
+            InviteError::Rejected(msg) => write!(f, "enrollment rejected: {msg}"),
+            InviteError::Serde(e) => write!(f, "json error: {e}"),
+            InviteError::Ureq(e) => write!(f, "http error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+impl From<std::io::Error> for InviteError {
+    fn from(e: std::io::Error) -> Self {
+        InviteError::FileIO(e)
+    }
+}
+
+impl From<IdError> for InviteError {
+    fn from(e: IdError) -> Self {
+        InviteError::Id(e)
+    }
+}
+
+impl From<NetworkError> for InviteError {
+    fn from(e: NetworkError) -> Self {
+        InviteError::Network(e)
+    }
+}
+
+impl From<serde_json::Error> for InviteError {
+    fn from(e: serde_json::Error) -> Self {
+        InviteError::Serde(e)
+    }
+}
+
+impl From<ureq::Error> for InviteError {
+    fn from(e: ureq::Error) -> Self {
+        InviteError::Ureq(Box::new(e))
+    }
+}
+
+/// The invites.json format, keyed by the SHA-256 of each invite's secret
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Invite {
+    pub name: String,
+    pub expires: u64,
+    pub used: bool,
+}
+
+pub type Invites = BTreeMap<String, Invite>;
+
+/// What the user is given: where to enroll, as whom, and the secret
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InviteToken {
+    pub url: String,
+    pub name: String,
+    pub secret: String,
+}
+
+/// The body of a POST to /enroll
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnrollRequest {
+    pub secret: String,
+    pub public_key: String,
+}
+
+pub fn read_invites_file(path: &str) -> Result<Invites, InviteError> {
+    if !Path::new(path).exists() {
+        return Ok(Invites::new());
+    }
+    let invites_json = fs::read_to_string(path)?;
+    let res = serde_json::from_str(&invites_json)?;
+    Ok(res)
+}
+
+pub fn write_invites_file(path: &str, invites: &Invites) -> Result<(), InviteError> {
+    let invites_json = serde_json::to_string_pretty(invites)?;
+    let mut file = create_private_file(path)?;
+    writeln!(file, "{}", invites_json)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn random_secret() -> Result<String, InviteError> {
+    let mut bytes = [0u8; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn secret_hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn encode_token(token: &InviteToken) -> Result<String, InviteError> {
+    let token_json = serde_json::to_string(token)?;
+    Ok(format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        URL_SAFE_NO_PAD.encode(token_json)
+    ))
+}
+
+pub fn decode_token(token: &str) -> Result<InviteToken, InviteError> {
+    let encoded = token
+        .trim()
+        .strip_prefix(TOKEN_PREFIX)
+        .ok_or(InviteError::BadToken(String::from("not a tulip invite")))?;
+    let token_json = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| InviteError::BadToken(e.to_string()))?;
+    let res = serde_json::from_slice(&token_json)?;
+    Ok(res)
+}
+
+/// Mint an invite for `name`, valid for `ttl` seconds, enrolling through the
+/// hub at `url`. Returns the token to give to the user.
+pub fn create_invite(
+    invites_path: &str,
+    name: &str,
+    url: &str,
+    ttl: u64,
+) -> Result<String, InviteError> {
+    let mut invites = read_invites_file(invites_path)?;
+    let secret = random_secret()?;
+    invites.insert(
+        secret_hash(&secret),
+        Invite {
+            name: name.to_string(),
+            expires: now() + ttl,
+            used: false,
+        },
+    );
+    write_invites_file(invites_path, &invites)?;
+    encode_token(&InviteToken {
+        url: url.to_string(),
+        name: name.to_string(),
+        secret,
+    })
+}
+
+/*
+ * The first host address of the network's subnet not already taken by the
+ * hub, a PublicEndpoint or a phonebook user
+ */
+fn allocate_ip(network: &Network, phonebook: &Phonebook) -> Result<String, InviteError> {
+    let subnet: Ipv4Net = network
+        .subnet
+        .parse()
+        .map_err(|_| InviteError::NoFreeIp(format!("{} is not an IPv4 subnet", network.subnet)))?;
+    let taken: Vec<Ipv4Addr> = phonebook
+        .values()
+        .map(|user| user.vpn_ip.as_str())
+        .chain(network.public_endpoints.iter().map(|e| e.vpn_ip.as_str()))
+        .chain([network.user.vpn_ip.as_str()])
+        .filter_map(|ip| ip.parse().ok())
+        .collect();
+    subnet
+        .hosts()
+        .find(|ip| !taken.contains(ip))
+        .map(|ip| ip.to_string())
+        .ok_or(InviteError::NoFreeIp(format!(
+            "no free IP left in {}",
+            network.subnet
+        )))
+}
+
+fn check_public_key(public_key: &str) -> Result<(), InviteError> {
+    match STANDARD.decode(public_key) {
+        Ok(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(InviteError::BadRequest(format!(
+            "{} is not a WireGuard key",
+            public_key
+        ))),
+    }
+}
+
+/// Hub side of `tulip join`: add the user to the phonebook (and to the
+/// running interface, if any), redeem the invite and return their network
+/// config. Revoked keys can't enroll.
+pub fn enroll(
+    ctx: &Context,
+    network_path: &str,
+    phonebook_path: &str,
+    invites_path: &str,
+    revocations_path: &str,
+    request: &EnrollRequest,
+) -> Result<Network, InviteError> {
+    check_public_key(&request.public_key)?;
+    let revocations = revocation::read_revocations_file(revocations_path)?;
+    if revocation::is_revoked(&revocations, &request.public_key) {
+        return Err(InviteError::BadRequest(String::from(
+            "this key was revoked",
+        )));
+    }
+    let mut invites = read_invites_file(invites_path)?;
+    let invite = invites
+        .get_mut(&secret_hash(&request.secret))
+        .ok_or(InviteError::BadToken(String::from("unknown invite")))?;
+    if invite.used {
+        return Err(InviteError::BadToken(String::from(
+            "invite was already used",
+        )));
+    }
+    if invite.expires < now() {
+        return Err(InviteError::BadToken(String::from("invite has expired")));
+    }
+    let hub = network::read_network_file(ctx, network_path)?;
+    let mut phonebook = phonebook::read_phonebook_file(ctx, phonebook_path.to_string())?;
+    if phonebook.contains_key(&invite.name) {
+        return Err(InviteError::Conflict(format!(
+            "{} is already in the phonebook",
+            &invite.name
+        )));
+    }
+    let vpn_ip = allocate_ip(&hub, &phonebook)?;
+    let name = invite.name.clone();
+    phonebook.insert(
+        name.clone(),
+        PrivateEndpoint {
+            name: name.clone(),
+            vpn_ip: vpn_ip.clone(),
+            public_key: request.public_key.clone(),
+            routes: vec![],
+            groups: vec![],
+            endpoints: vec![],
+            observed_endpoint: None,
+            last_handshake: None,
+        },
+    );
+    phonebook::write_phonebook_file(ctx, phonebook_path, &phonebook)?;
+    invite.used = true;
+    write_invites_file(invites_path, &invites)?;
+    /*
+     * The user is in the phonebook now, so a hub that isn't running (or
+     * fails to add them) shouldn't fail their enrollment: the next start or
+     * watch adds them
+     */
+    if let Err(e) = network::refresh(
+        ctx,
+        &hub,
+        Some(phonebook),
+        Some(revocations),
+        false,
+        0,
+        None,
+    ) {
+        ctx.report.warning(&format!(
+            "couldn't add {} to the running interface: {e}",
+            &name
+        ));
+    }
+    Ok(Network {
+        user: UserEndpoint {
+            name,
+            vpn_ip,
+            port: None,
+            mtu: None,
+        },
+        /*
+         * The policy and visibility rules, and the hub's namespace, are the
+         * hub's business
+         */
+        policy: vec![],
+        visibility: vec![],
+        netns: None,
+        ..hub
+    })
+}
+
+/// User side: generate a {private,public}_id.json in the cwd, enroll with the
+/// hub named in the invite and write the returned
+/// `<name>_tulip_network.json`.
+/// Returns the paths of the network config and the private ID.
+pub fn join(ctx: &Context, token: &str) -> Result<(String, String), InviteError> {
+    let token = decode_token(token)?;
+    let pub_id = id::gen_id_files(ctx, token.name.clone())?;
+    let url = format!("{}/enroll", token.url.trim_end_matches('/'));
+    let res = ureq::post(&url).send_json(EnrollRequest {
+        secret: token.secret,
+        public_key: pub_id.public_key,
+    });
+    let network: Network = match res {
+        Ok(res) => res.into_json()?,
+        Err(ureq::Error::Status(code, res)) => {
+            let msg = res.into_string().unwrap_or_default();
+            return Err(InviteError::Rejected(format!("{} {}", code, msg.trim())));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let network_json = serde_json::to_string_pretty(&network)?;
+    let out_path = format!("{}_tulip_network.json", &token.name);
+    let mut out_file = create_private_file(&out_path)?;
+    ctx.report.say(&format!("writing {}", &out_path));
+    writeln!(out_file, "{}", &network_json)?;
+    Ok((out_path, format!("{}_private_id.json", &token.name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{network, phonebook_of, user};
+    use serde_json::json;
+
+    fn phonebook(vpn_ips: &[&str]) -> Phonebook {
+        phonebook_of(
+            vpn_ips
+                .iter()
+                .enumerate()
+                .map(|(i, vpn_ip)| user(&format!("user{i}"), vpn_ip, json!({})))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let token = encode_token(&InviteToken {
+            url: String::from("http://10.0.0.1:8443"),
+            name: String::from("janie"),
+            secret: String::from("s3cret"),
+        })
+        .unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        let decoded = decode_token(&format!(" {token}\n")).unwrap();
+        assert_eq!(decoded.url, "http://10.0.0.1:8443");
+        assert_eq!(decoded.name, "janie");
+        assert_eq!(decoded.secret, "s3cret");
+    }
+
+    #[test]
+    fn bad_tokens_are_rejected() {
+        assert!(matches!(
+            decode_token("not-an-invite"),
+            Err(InviteError::BadToken(_))
+        ));
+        assert!(matches!(
+            decode_token("tulip-invite:!!!"),
+            Err(InviteError::BadToken(_))
+        ));
+        assert!(matches!(
+            decode_token(&format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode("{}"))),
+            Err(InviteError::Serde(_))
+        ));
+    }
+
+    #[test]
+    fn allocate_ip_skips_taken_addresses() {
+        let ip = allocate_ip(
+            &network(json!({ "subnet": "10.0.0.0/24" })),
+            &phonebook(&["10.0.0.3", "10.0.0.5"]),
+        );
+        assert_eq!(ip.unwrap(), "10.0.0.4");
+    }
+
+    #[test]
+    fn allocate_ip_fails_on_a_full_subnet() {
+        let ip = allocate_ip(
+            &network(json!({ "subnet": "10.0.0.0/30" })),
+            &phonebook(&[]),
+        );
+        assert!(matches!(ip, Err(InviteError::NoFreeIp(_))));
+        let ip = allocate_ip(&network(json!({ "subnet": "fd00::/64" })), &phonebook(&[]));
+        assert!(matches!(ip, Err(InviteError::NoFreeIp(_))));
+    }
+}
@@ -0,0 +1,69 @@
+//! Tulip (tulip.network) as a library
+//!
+//! The `tulip` binary is a thin command line over this crate: everything it
+//! does (reading IDs and networks, curling phonebooks, writing WireGuard
+//! configs, starting and stopping networks) is available here, and returns a
+//! `Result` instead of panicking.
+//!
+//! The most common pieces are re-exported at the top:
+//!
+//! ```no_run
+//! use tulip_net::{Context, Network, PrivId, StartInput, WgConfSection};
+//!
+//! fn provision() -> Result<(), Box<dyn std::error::Error>> {
+//!     let ctx = &Context::default();
+//!     let network: Network = tulip_net::read_network_file(ctx, "sandringham_tulip_network.json")?;
+//!     let priv_id: PrivId = tulip_net::read_id_file("private_id.json")?;
+//!     let phonebook = tulip_net::curl_phonebook_list(ctx, &network, 3)?;
+//!     println!("{}", phonebook.wg_conf_section(()));
+//!     tulip_net::start(
+//!         ctx,
+//!         network,
+//!         priv_id,
+//!         None,
+//!         None,
+//!         &StartInput {
+//!             timeout: 30,
+//!             ..Default::default()
+//!         },
+//!     )?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Starting, stopping and refreshing a network run `ip`, `wg`, `sysctl` and
+//! `nft`, through the `Context`'s escalation program when needed (see `misc`).
+//! Their progress messages go to stdout, or to stderr when the `Context`'s
+//! `Reporter` is in json mode.
+
+/// How to run: dry runs, privilege escalation, namespaces, formats and reporting
+pub mod context;
+/// Tulip IDs (WireGuard keypairs with a name) and key rotation
+pub mod id;
+/// Invite-based enrollment
+pub mod invite;
+/// Running (privileged) commands and writing private files
+pub mod misc;
+/// Tulip networks: their configs, phonebooks, and starting and stopping them
+pub mod network;
+/// Machine-readable results (`--json`)
+pub mod report;
+/// JSON Schemas of the Tulip files
+pub mod schema;
+/// The hub's HTTP endpoint (enrollment, phonebook, revocations)
+pub mod serve;
+/// systemd units for starting a network at boot
+pub mod service;
+/// The named network store
+pub mod store;
+#[cfg(test)]
+mod test_fixtures;
+
+pub use context::Context;
+pub use id::{gen_id, read_id_file, IdError, PrivId, PubId};
+pub use network::{
+    phonebook::{curl_phonebook_list, read_phonebook_file, Phonebook},
+    read_network_file, refresh, start, status, stop,
+    wg_conf::WgConfSection,
+    Network, NetworkError, StartInput, SyncInput,
+};
@@ -1,14 +1,30 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::{panic, process, thread};
+use tulip_net::{
+    misc,
+    network::{
+        format::{Format, FORMATS},
+        StartInput, SyncInput,
+    },
+    report::Reporter,
+    schema, Context,
+};
 
-mod id;
-mod misc;
-mod network;
 mod tasks;
 
 /// Tulip (tulip.network)
 #[derive(Debug, Parser)]
 #[command(author, about, version = option_env!("TULIP_VERSION").unwrap_or("dev"))]
 struct Cli {
+    /// Program to get root or CAP_NET_ADMIN with, when tulip doesn't already have them
+    #[arg(long, global = true, env = "TULIP_ESCALATE", default_value = misc::DEFAULT_ESCALATION)]
+    escalate: String,
+    /// Format (json, toml or yaml) of network and phonebook files whose extension doesn't say
+    #[arg(long, global = true, value_parser = FORMATS)]
+    format: Option<String>,
+    /// Print one JSON result (files written, interface, peers, errors) on stdout
+    #[arg(long, global = true)]
+    json: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -43,69 +59,538 @@ enum Commands {
     },
     /// Generate a WireGuard config for a Tulip user. For use by Tulip network user
     GenWgConf {
+        /// Name of a network in your network store
+        name: Option<String>,
         /// Kind of network config (qr or wg)
         #[arg(short, long, default_value_t = String::from("qr"))]
         kind: String,
         /// Path to this Tulip server's tulip_network.json
-        #[arg(long)]
-        network: String,
+        #[arg(long, required_unless_present = "name")]
+        network: Option<String>,
         /// Output directory for the WireGuard config file
         #[arg(short, long, default_value_t = String::from("./"))]
         output: String,
         /// Path to private_id.json
         #[arg(short, long)]
-        priv_id: String,
+        priv_id: Option<String>,
+        /// Route all traffic through the public endpoint with this name
+        #[arg(short, long)]
+        exit_node: Option<String>,
     },
-    /// Start a Tulip network
-    Start {
+    /// Manage your Tulip ID
+    Id {
+        #[command(subcommand)]
+        command: IdCommands,
+    },
+    /// Install systemd units that start a Tulip network at boot (requires root/sudo privilege)
+    InstallService {
+        /// Name of a network in your network store
+        name: Option<String>,
         /// Path to tulip_network.json
-        #[arg(short, long)]
-        network: String,
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
         /// Path to phonebook.json (required if in server mode)
         #[arg(short, long)]
         phonebook: Option<String>,
         /// Path to private_id.json
+        #[arg(long)]
+        priv_id: Option<String>,
+        /// Path to revocations.json (in server mode)
+        #[arg(long)]
+        revocations: Option<String>,
+        /// Start in server mode
+        #[arg(short, long, default_value_t = false)]
+        server: bool,
+        /// Timeout for querying the phonebook (seconds)
+        #[arg(short, long, default_value_t = 3)]
+        timeout: u64,
+        /// Also install a timer that refreshes the phonebook every N seconds
         #[arg(short, long)]
-        priv_id: String,
-        /// Start in server mode (enable ipv4 and ipv6 forwarding kernel parameters)
+        refresh: Option<u64>,
+        /// Enable and start the units right away
+        #[arg(short, long, default_value_t = false)]
+        enable: bool,
+    },
+    /// Manage invites to your Tulip network. For use by a Tulip network admin
+    Invite {
+        #[command(subcommand)]
+        command: InviteCommands,
+    },
+    /// Join a Tulip network with an invite: generate your ID and enroll with the network's hub
+    Join {
+        /// The invite token given to you by a Tulip network admin
+        token: String,
+    },
+    /// Manage the named networks in your network store (~/.config/tulip/networks)
+    Networks {
+        #[command(subcommand)]
+        command: NetworksCommands,
+    },
+    /// Revoke a Tulip user's key and remove them from the phonebook. For use by a Tulip network admin
+    Revoke {
+        /// The nickname of the Tulip user whose key you're revoking
+        name: String,
+        /// Path to phonebook.json
+        #[arg(short, long)]
+        phonebook: String,
+        /// Path to revocations.json (served next to phonebook.json)
+        #[arg(short, long, default_value_t = String::from("revocations.json"))]
+        revocations: String,
+        /// Why the key is revoked
+        #[arg(long, default_value_t = String::new())]
+        reason: String,
+    },
+    /// Print the JSON Schema of a Tulip file, for editors and CI
+    Schema {
+        /// Which file
+        #[arg(value_parser = schema::KINDS)]
+        kind: String,
+    },
+    /// Serve this hub's enrollment endpoint (POST /enroll). For use by a Tulip network admin
+    Serve {
+        /// Address to listen on (repeat to listen on several). Put a TLS proxy in front of public ones
+        #[arg(short, long, default_values_t = [String::from("127.0.0.1:8443")])]
+        listen: Vec<String>,
+        /// Path to this Tulip server's tulip_network.json
+        #[arg(short, long)]
+        network: String,
+        /// Path to phonebook.json
+        #[arg(short, long)]
+        phonebook: String,
+        /// Path to invites.json
+        #[arg(short, long, default_value_t = String::from("invites.json"))]
+        invites: String,
+        /// Path to revocations.json (revoked keys can't enroll, and it's served at /revocations.json)
+        #[arg(long, default_value_t = String::from("revocations.json"))]
+        revocations: String,
+        /// Serve this dynamic phonebook (see watch --dynamic-phonebook) at /phonebook.json instead of the phonebook
+        #[arg(long)]
+        dynamic_phonebook: Option<String>,
+    },
+    /// Start a Tulip network
+    Start {
+        /// Name of a network in your network store
+        name: Option<String>,
+        /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Path to phonebook.json (required if in server mode)
+        #[arg(short, long, env = "TULIP_PHONEBOOK")]
+        phonebook: Option<String>,
+        /// Path to private_id.json
+        #[arg(long)]
+        priv_id: Option<String>,
+        /// Path to revocations.json (in server mode)
+        #[arg(long, env = "TULIP_REVOCATIONS")]
+        revocations: Option<String>,
+        /// Start in server mode (enable ipv4 and ipv6 forwarding kernel parameters, until stop)
         #[arg(short, long, default_value_t = false)]
         server: bool,
         /// Timeout for querying the phonebook (seconds)
         #[arg(short, long, default_value_t = 3)]
         timeout: u64,
+        /// Route all traffic through the public endpoint with this name
+        #[arg(short, long)]
+        exit_node: Option<String>,
+        /// With --exit-node, block all traffic that doesn't go through the tunnel
+        #[arg(short, long, default_value_t = false, requires = "exit_node")]
+        kill_switch: bool,
+        /// Peer directly with users that publish endpoints in the phonebook
+        #[arg(short, long, default_value_t = false, conflicts_with = "server")]
+        mesh: bool,
+        /// In server mode, publish the phonebook with each user's observed endpoint here
+        #[arg(long, requires = "server")]
+        dynamic_phonebook: Option<String>,
+        /// Move the interface into this network namespace
+        #[arg(long)]
+        netns: Option<String>,
+        /// Print the commands and files that would be applied, without applying them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Show the interface and peers of a running Tulip network
+    Status {
+        /// Name of a network in your network store
+        name: Option<String>,
+        /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Network namespace the interface is in
+        #[arg(long)]
+        netns: Option<String>,
     },
     /// Stop a Tulip network
     Stop {
+        /// Name of a network in your network store
+        name: Option<String>,
         /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Network namespace the interface is in
+        #[arg(long)]
+        netns: Option<String>,
+        /// Print the commands and files that would be applied, without applying them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Change only what differs between a running Tulip network and its config and phonebook
+    Sync {
+        /// Name of a network in your network store
+        name: Option<String>,
+        /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Path to phonebook.json (in server mode, read instead of curled)
         #[arg(short, long)]
-        network: String,
+        phonebook: Option<String>,
+        /// Path to private_id.json
+        #[arg(long)]
+        priv_id: Option<String>,
+        /// Path to revocations.json (in server mode)
+        #[arg(long)]
+        revocations: Option<String>,
+        /// Timeout for querying the phonebook (seconds)
+        #[arg(short, long, default_value_t = 3)]
+        timeout: u64,
+        /// The public endpoint the network routes all traffic through, if started with --exit-node
+        #[arg(short, long)]
+        exit_node: Option<String>,
+        /// Only show the changes
+        #[arg(short, long, default_value_t = false)]
+        diff: bool,
+        /// Network namespace the interface is in
+        #[arg(long)]
+        netns: Option<String>,
     },
     /// Testing command. Herein lies DANGER
     Test,
+    /// Refresh the phonebook of a running Tulip network
+    Watch {
+        /// Name of a network in your network store
+        name: Option<String>,
+        /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Path to phonebook.json (in server mode, re-read instead of curled)
+        #[arg(short, long, env = "TULIP_PHONEBOOK")]
+        phonebook: Option<String>,
+        /// Path to revocations.json (in server mode)
+        #[arg(long, env = "TULIP_REVOCATIONS")]
+        revocations: Option<String>,
+
+        /// Timeout for querying the phonebook (seconds)
+        #[arg(short, long, default_value_t = 3)]
+        timeout: u64,
+        /// Keep refreshing every N seconds instead of refreshing once
+        #[arg(short, long)]
+        interval: Option<u64>,
+        /// Peer directly with users that publish endpoints in the phonebook
+        #[arg(short, long, default_value_t = false)]
+        mesh: bool,
+        /// In server mode, publish the phonebook with each user's observed endpoint here
+        #[arg(long)]
+        dynamic_phonebook: Option<String>,
+        /// Network namespace the interface is in
+        #[arg(long)]
+        netns: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum IdCommands {
+    /// Verify a rotation request and swap the user's key in the phonebook. For use by a Tulip network admin
+    AcceptRotation {
+        /// Path to the user's rotation_request.json
+        request: String,
+        /// Path to phonebook.json
+        #[arg(short, long)]
+        phonebook: String,
+        /// Path to this Tulip server's private_id.json
+        #[arg(long)]
+        priv_id: String,
+    },
+    /// Generate a new keypair and a rotation request proving you own the old one
+    Rotate {
+        /// Name of a network in your network store
+        name: Option<String>,
+        /// Path to tulip_network.json
+        #[arg(short, long, required_unless_present = "name")]
+        network: Option<String>,
+        /// Path to your current private_id.json
+        #[arg(long)]
+        priv_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum InviteCommands {
+    /// Mint a single-use, expiring invite token for a new Tulip user
+    Create {
+        /// The nickname of the Tulip user you're inviting
+        #[arg(short, long)]
+        name: String,
+        /// Public URL of this hub's `tulip serve` endpoint (e.g. https://vpn.example.com:8443)
+        #[arg(short, long)]
+        url: String,
+        /// Path to invites.json
+        #[arg(short, long, default_value_t = String::from("invites.json"))]
+        invites: String,
+        /// How long the invite is valid for (seconds)
+        #[arg(short, long, default_value_t = 86400)]
+        ttl: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum NetworksCommands {
+    /// Copy a tulip_network.json (and optionally your ID and phonebook) into the network store
+    Import {
+        /// Path to tulip_network.json
+        file: String,
+        /// Path to private_id.json
+        #[arg(long)]
+        priv_id: Option<String>,
+        /// Path to phonebook.json
+        #[arg(short, long)]
+        phonebook: Option<String>,
+        /// Overwrite a private ID already in the network store
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+    /// List the networks in the network store
+    List,
+}
+
+/*
+ * Print the Report (with --json) and exit with a matching code
+ */
+fn finish(reporter: &Reporter) -> ! {
+    let report = reporter.report();
+    if reporter.json() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("json issue")
+        );
+    }
+    process::exit(if report.ok { 0 } else { 1 })
+}
+
+/*
+ * Tasks panic on errors (`.expect("... problem")`), so with --json, a
+ * panic on the main thread becomes the Report's error
+ */
+fn exit_on_panic(reporter: &Reporter) {
+    if !reporter.json() {
+        return;
+    }
+    let reporter = reporter.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().name() != Some("main") {
+            return default_hook(info);
+        }
+        let payload = info.payload();
+        let message = match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => String::from("panic"),
+        };
+        reporter.error(&message);
+        finish(&reporter);
+    }));
 }
 
 fn main() {
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let ctx = &Context {
+        dry_run: false,
+        escalation: args.escalate,
+        privileges: misc::privileges(),
+        netns: None,
+        format: args.format.as_deref().and_then(Format::from_name),
+        report: Reporter::new(args.json, matches.subcommand_name().unwrap_or_default()),
+    };
+    exit_on_panic(&ctx.report);
     match args.command {
         Commands::Debug { onoff } => tasks::debug(onoff),
-        Commands::GenId { name } => tasks::gen_id(name),
+        Commands::GenId { name } => tasks::gen_id(ctx, name),
         Commands::Start {
+            name,
             network,
             priv_id,
+            revocations,
             server,
             phonebook,
             timeout,
+            exit_node,
+            kill_switch,
+            mesh,
+            dynamic_phonebook,
+            netns,
+            dry_run,
         } => {
-            if server && phonebook.is_none() {
-                eprintln!("need a --phonebook in --server mode");
+            let paths = tasks::resolve_paths(name, network, priv_id, phonebook, server);
+            if server && paths.phonebook.is_none() {
+                ctx.report.error("need a --phonebook in --server mode");
+            } else if let Some(priv_id) = paths.priv_id {
+                tasks::start_network(
+                    &Context {
+                        dry_run,
+                        ..ctx.with_netns(netns)
+                    },
+                    paths.network,
+                    priv_id,
+                    paths.phonebook,
+                    revocations,
+                    StartInput {
+                        server,
+                        timeout,
+                        exit_node,
+                        kill_switch,
+                        mesh,
+                        dynamic_phonebook,
+                    },
+                );
             } else {
-                tasks::start_network(network, priv_id, server, phonebook, timeout);
+                ctx.report.error("need a --priv-id");
+            }
+        }
+        Commands::InstallService {
+            name,
+            network,
+            phonebook,
+            priv_id,
+            revocations,
+            server,
+            timeout,
+            refresh,
+            enable,
+        } => {
+            let paths = tasks::resolve_paths(name, network, priv_id, phonebook, server);
+            if server && paths.phonebook.is_none() {
+                ctx.report.error("need a --phonebook in --server mode");
+            } else if paths.priv_id.is_none() {
+                ctx.report.error("need a --priv-id");
+            } else {
+                tasks::install_service(ctx, paths, revocations, server, timeout, refresh, enable);
+            }
+        }
+        Commands::Id { command } => match command {
+            IdCommands::AcceptRotation {
+                request,
+                phonebook,
+                priv_id,
+            } => tasks::accept_rotation(ctx, request, phonebook, priv_id),
+            IdCommands::Rotate {
+                name,
+                network,
+                priv_id,
+            } => {
+                let paths = tasks::resolve_paths(name, network, priv_id, None, false);
+                match paths.priv_id {
+                    Some(priv_id) => tasks::rotate_id(ctx, paths.network, priv_id),
+                    None => ctx.report.error("need a --priv-id"),
+                }
+            }
+        },
+        Commands::Invite { command } => match command {
+            InviteCommands::Create {
+                name,
+                url,
+                invites,
+                ttl,
+            } => tasks::create_invite(ctx, invites, name, url, ttl),
+        },
+        Commands::Join { token } => tasks::join(ctx, token),
+        Commands::Revoke {
+            name,
+            phonebook,
+            revocations,
+            reason,
+        } => tasks::revoke(ctx, name, phonebook, revocations, reason),
+        Commands::Schema { kind } => tasks::schema(ctx, &kind),
+        Commands::Serve {
+            listen,
+            network,
+            phonebook,
+            invites,
+            revocations,
+            dynamic_phonebook,
+        } => tasks::serve(
+            ctx,
+            listen,
+            network,
+            phonebook,
+            invites,
+            revocations,
+            dynamic_phonebook,
+        ),
+        Commands::Networks { command } => match command {
+            NetworksCommands::Import {
+                file,
+                priv_id,
+                phonebook,
+                force,
+            } => tasks::import_network(ctx, file, priv_id, phonebook, force),
+            NetworksCommands::List => tasks::list_networks(ctx),
+        },
+        Commands::Status {
+            name,
+            network,
+            netns,
+        } => {
+            let paths = tasks::resolve_paths(name, network, None, None, false);
+            tasks::status_network(&ctx.with_netns(netns), paths.network)
+        }
+        Commands::Stop {
+            name,
+            network,
+            netns,
+            dry_run,
+        } => {
+            let paths = tasks::resolve_paths(name, network, None, None, false);
+            tasks::stop_network(
+                &Context {
+                    dry_run,
+                    ..ctx.with_netns(netns)
+                },
+                paths.network,
+            )
+        }
+        Commands::Sync {
+            name,
+            network,
+            phonebook,
+            priv_id,
+            revocations,
+            timeout,
+            exit_node,
+            diff,
+            netns,
+        } => {
+            let mut paths = tasks::resolve_paths(name, network, priv_id, phonebook, false);
+            match paths.priv_id.take() {
+                Some(priv_id) => tasks::sync_network(
+                    &ctx.with_netns(netns),
+                    paths,
+                    priv_id,
+                    revocations,
+                    SyncInput {
+                        timeout,
+                        exit_node,
+                        diff_only: diff,
+                    },
+                ),
+                None => ctx.report.error("need a --priv-id"),
             }
         }
-        Commands::Stop { network } => tasks::stop_network(network),
         Commands::Test => {
             let version = option_env!("CLI_GIT_COMMIT").unwrap_or("dev");
-            println!("{version}");
+            ctx.report.say(version);
         }
         Commands::GenNetConf {
             name,
@@ -113,13 +598,56 @@ fn main() {
             output,
             phonebook,
         } => {
-            tasks::write_network_json_file(output, name, network, phonebook);
+            tasks::write_network_conf_file(ctx, output, name, network, phonebook);
         }
         Commands::GenWgConf {
+            name,
             kind,
             output,
             priv_id,
             network,
-        } => tasks::write_wg_conf_file(&kind, &output, &network, &priv_id),
+            exit_node,
+        } => {
+            let paths = tasks::resolve_paths(name, network, priv_id, None, false);
+            match paths.priv_id {
+                Some(priv_id) => tasks::write_wg_conf_file(
+                    ctx,
+                    &kind,
+                    &output,
+                    &paths.network,
+                    &priv_id,
+                    exit_node,
+                ),
+                None => ctx.report.error("need a --priv-id"),
+            }
+        }
+        Commands::Watch {
+            name,
+            network,
+            phonebook,
+            revocations,
+            timeout,
+            interval,
+            mesh,
+            dynamic_phonebook,
+            netns,
+        } => {
+            let paths = tasks::resolve_paths(name, network, None, phonebook, false);
+            if dynamic_phonebook.is_some() && paths.phonebook.is_none() {
+                ctx.report
+                    .error("need a --phonebook to publish a --dynamic-phonebook");
+            } else {
+                tasks::watch_network(
+                    &ctx.with_netns(netns),
+                    paths,
+                    revocations,
+                    dynamic_phonebook,
+                    mesh,
+                    timeout,
+                    interval,
+                )
+            }
+        }
     }
+    finish(&ctx.report)
 }
@@ -1,66 +1,403 @@
-use core::time;
+use crate::context::Context;
 use std::{
-    ffi::OsStr,
+    env,
+    ffi::{OsStr, OsString},
     fs::{self, OpenOptions},
-    io::{self, Write},
-    os::unix::prelude::OpenOptionsExt,
-    process::{Command, Stdio},
-    thread::sleep,
+    io::{self, Read, Write},
+    os::{
+        fd::AsFd,
+        unix::{fs::DirBuilderExt, prelude::OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
 };
 
-pub fn create_private_file(path: &str) -> Result<fs::File, io::Error> {
+/*
+ * Dry runs
+ * When the Context says so, exec* print the commands they would run, and
+ * write_staged_file prints the files it would write (without their keys),
+ * instead of doing it. exec_output and exec_privileged_output still run, since
+ * they only read state.
+ */
+fn command_line(cmd: &str, args: &[OsString]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                format!("'{}'", arg)
+            } else {
+                arg.to_string()
+            }
+        })
+        .collect();
+    format!("{} {}", cmd, args.join(" "))
+}
+
+fn print_command(ctx: &Context, cmd: &str, args: &[OsString]) {
+    ctx.report.say(&command_line(cmd, args));
+}
+
+/*
+ * A command that ran but failed is an error too, naming the command
+ */
+fn check_status(cmd: &str, args: &[OsString], status: ExitStatus) -> Result<(), io::Error> {
+    if status.success() {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "`{}` failed ({})",
+        command_line(cmd, args),
+        status
+    )))
+}
+
+/// Hide the keys of a WireGuard config
+pub fn redact_keys(conf: &str) -> String {
+    conf.lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if ["PrivateKey", "PresharedKey"].contains(&key.trim()) => {
+                format!("{}= (redacted)", key)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Write a file that is only meant to be handed to a command (wg, nft...)
+pub fn write_staged_file(ctx: &Context, path: &str, contents: &str) -> Result<(), io::Error> {
+    if ctx.dry_run {
+        ctx.report
+            .say(&format!("# {}\n{}", path, redact_keys(contents)));
+        return Ok(());
+    }
+    create_private_file(path)?.write_all(contents.as_bytes())
+}
+
+pub fn create_private_file<P: AsRef<Path>>(path: P) -> Result<fs::File, io::Error> {
     OpenOptions::new()
         .create(true)
         .read(true)
         .write(true)
         .truncate(true)
         .mode(0o600)
-        .open(&path)
+        .open(path)
 }
 
-pub fn countdown(n: i64) -> Result<(), std::io::Error> {
-    if n <= 0 {
-        Ok(())
-    } else {
-        for i in (1..(n + 1)).rev() {
-            print!("{i}...");
-            let _ = io::stdout().flush();
-            sleep(time::Duration::from_secs(1));
-        }
-        print!("\n");
-        Ok(())
-    }
+/// A new directory only we can use, in the temporary directory. It fails
+/// rather than reuse one someone else made.
+pub fn create_private_dir(prefix: &str) -> Result<PathBuf, io::Error> {
+    let mut bytes = [0u8; 8];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let suffix: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let path = env::temp_dir().join(format!("{}_{}", prefix, suffix));
+    fs::DirBuilder::new().mode(0o700).create(&path)?;
+    Ok(path)
 }
 
 pub fn set_kernel_parameter(path: &str, value: &str) -> Result<(), std::io::Error> {
     fs::write(path, value)
 }
 
-pub fn exec<I, S>(cmd: &str, args: I) -> Result<(), std::io::Error>
+/*
+ * Privileges
+ * The networking commands need CAP_NET_ADMIN, and the rest of the privileged
+ * commands (systemctl, install, anything inside a network namespace) need
+ * root. exec_privileged* run them as they are when we already have that,
+ * and through the Context's escalation program (sudo, doas, pkexec...)
+ * otherwise.
+ */
+pub const DEFAULT_ESCALATION: &str = "sudo";
+const CAP_NET_ADMIN: u32 = 12;
+const NET_ADMIN_COMMANDS: [&str; 4] = ["ip", "nft", "sysctl", "wg"];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Privileges {
+    /// Our effective uid is 0
+    pub root: bool,
+    /// We have CAP_NET_ADMIN
+    pub net_admin: bool,
+}
+
+/// Our effective uid and capabilities, from /proc/self/status. They're read
+/// once, into the Context.
+pub fn privileges() -> Privileges {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = |name: &str| -> Option<String> {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|value| value.trim().to_string())
+    };
+    let root = field("Uid:")
+        .and_then(|uids| uids.split_whitespace().nth(1).map(String::from))
+        .is_some_and(|euid| euid == "0");
+    let net_admin = field("CapEff:")
+        .and_then(|caps| u64::from_str_radix(&caps, 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0);
+    Privileges { root, net_admin }
+}
+
+fn needs_root_only(args: &[OsString]) -> bool {
+    let program = args.first().and_then(|a| a.to_str()).unwrap_or_default();
+    let second = args.get(1).and_then(|a| a.to_str()).unwrap_or_default();
+    !NET_ADMIN_COMMANDS.contains(&program) || (program == "ip" && ["-n", "netns"].contains(&second))
+}
+
+fn privileged_args(ctx: &Context, args: Vec<OsString>) -> (String, Vec<OsString>) {
+    let privileges = ctx.privileges;
+    if privileges.root || (privileges.net_admin && !needs_root_only(&args)) {
+        let mut args = args.into_iter();
+        let program = args
+            .next()
+            .map(|program| program.to_string_lossy().to_string())
+            .unwrap_or_default();
+        (program, args.collect())
+    } else {
+        (ctx.escalation.clone(), args)
+    }
+}
+
+fn in_path(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+/// Fail early, rather than on the first privileged command, when we can't
+/// run them
+pub fn check_privileges(ctx: &Context, root_only: bool) -> Result<(), io::Error> {
+    let privileges = ctx.privileges;
+    if ctx.dry_run || privileges.root || (privileges.net_admin && !root_only && ctx.netns.is_none())
+    {
+        return Ok(());
+    }
+    let program = &ctx.escalation;
+    if in_path(program) {
+        return Ok(());
+    }
+    let needed = if root_only || ctx.netns.is_some() {
+        "root"
+    } else {
+        "root or CAP_NET_ADMIN"
+    };
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "tulip needs {} and would use {} to get it, but {} isn't installed (see --escalate)",
+            needed, program, program
+        ),
+    ))
+}
+
+/*
+ * Network namespace
+ * When the Context has one, the privileged networking commands below run
+ * inside it, with `ip -n` or `ip netns exec`, except for
+ * exec_privileged_in_init_netns.
+ */
+const NETNS_COMMANDS: [&str; 5] = ["ip", "nft", "ping", "sysctl", "wg"];
+
+fn netns_args<I, S>(ctx: &Context, args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let args: Vec<OsString> = args.into_iter().map(|a| a.as_ref().to_owned()).collect();
+    let (Some(netns), Some(program)) = (
+        ctx.netns.clone(),
+        args.first().and_then(|a| a.to_str()).map(String::from),
+    ) else {
+        return args;
+    };
+    let prefix: Vec<&str> = match program.as_str() {
+        "ip" => vec!["ip", "-n", &netns],
+        program if NETNS_COMMANDS.contains(&program) => {
+            vec!["ip", "netns", "exec", &netns, program]
+        }
+        _ => return args,
+    };
+    prefix
+        .into_iter()
+        .map(OsString::from)
+        .chain(args.into_iter().skip(1))
+        .collect()
+}
+
+/// Run a command that needs privileges, e.g. ["wg", "setconf", ...]
+pub fn exec_privileged<I, S>(ctx: &Context, args: I) -> Result<(), std::io::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let (cmd, args) = privileged_args(ctx, netns_args(ctx, args));
+    exec_args(ctx, &cmd, args)
+}
+
+/// Like exec_privileged, but outside of the network namespace, if any
+pub fn exec_privileged_in_init_netns<I, S>(ctx: &Context, args: I) -> Result<(), std::io::Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    Command::new(cmd)
-        .args(args)
+    let (cmd, args) = privileged_args(
+        ctx,
+        args.into_iter().map(|a| a.as_ref().to_owned()).collect(),
+    );
+    exec_args(ctx, &cmd, args)
+}
+
+fn exec_args(ctx: &Context, cmd: &str, args: Vec<OsString>) -> Result<(), std::io::Error> {
+    if ctx.dry_run {
+        print_command(ctx, cmd, &args);
+        return Ok(());
+    }
+    /*
+     * With --json, stdout is for the Report
+     */
+    let stdout = if ctx.report.json() {
+        Stdio::from(io::stderr().as_fd().try_clone_to_owned()?)
+    } else {
+        Stdio::inherit()
+    };
+    let status = Command::new(cmd)
+        .args(&args)
         .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
+        .stdout(stdout)
         .stderr(Stdio::inherit())
         .spawn()?
         .wait()?;
-    Ok(())
+    check_status(cmd, &args, status)
 }
 
-pub fn exec_silent<I, S>(cmd: &str, args: I) -> Result<(), std::io::Error>
+pub fn exec_silent<I, S>(ctx: &Context, cmd: &str, args: I) -> Result<(), std::io::Error>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    Command::new(cmd)
-        .args(args)
+    exec_silent_args(
+        ctx,
+        cmd,
+        args.into_iter().map(|a| a.as_ref().to_owned()).collect(),
+    )
+}
+
+pub fn exec_privileged_silent<I, S>(ctx: &Context, args: I) -> Result<(), std::io::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let (cmd, args) = privileged_args(ctx, netns_args(ctx, args));
+    exec_silent_args(ctx, &cmd, args)
+}
+
+fn exec_silent_args(ctx: &Context, cmd: &str, args: Vec<OsString>) -> Result<(), std::io::Error> {
+    if ctx.dry_run {
+        print_command(ctx, cmd, &args);
+        return Ok(());
+    }
+    let status = Command::new(cmd)
+        .args(&args)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()?
         .wait()?;
-    Ok(())
+    check_status(cmd, &args, status)
+}
+
+pub fn exec_output<I, S>(cmd: &str, args: I) -> Result<String, std::io::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    exec_output_args(
+        cmd,
+        args.into_iter().map(|a| a.as_ref().to_owned()).collect(),
+    )
+}
+
+pub fn exec_privileged_output<I, S>(ctx: &Context, args: I) -> Result<String, std::io::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let (cmd, args) = privileged_args(ctx, netns_args(ctx, args));
+    exec_output_args(&cmd, args)
+}
+
+fn exec_output_args(cmd: &str, args: Vec<OsString>) -> Result<String, std::io::Error> {
+    let output = Command::new(cmd)
+        .args(&args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    check_status(cmd, &args, output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn ctx(root: bool, net_admin: bool) -> Context {
+        Context {
+            escalation: String::from("doas"),
+            privileges: Privileges { root, net_admin },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_networking_commands_make_do_with_net_admin() {
+        assert!(!needs_root_only(&args(&["ip", "link", "add"])));
+        assert!(!needs_root_only(&args(&["wg", "setconf"])));
+        assert!(!needs_root_only(&args(&["nft", "-f", "/tmp/x.nft"])));
+        assert!(!needs_root_only(&args(&["sysctl", "-w", "a=1"])));
+        assert!(needs_root_only(&args(&["ip", "-n", "vpn", "link"])));
+        assert!(needs_root_only(&args(&[
+            "ip", "netns", "exec", "vpn", "wg"
+        ])));
+        assert!(needs_root_only(&args(&["systemctl", "daemon-reload"])));
+        assert!(needs_root_only(&args(&["install", "-D"])));
+    }
+
+    #[test]
+    fn privileged_args_escalate_only_when_needed() {
+        let wg = args(&["wg", "show"]);
+        let netns = args(&["ip", "netns", "exec", "vpn", "wg", "show"]);
+        assert_eq!(
+            privileged_args(&ctx(true, false), netns.clone()),
+            (String::from("ip"), netns[1..].to_vec())
+        );
+        assert_eq!(
+            privileged_args(&ctx(false, true), wg.clone()),
+            (String::from("wg"), wg[1..].to_vec())
+        );
+        assert_eq!(
+            privileged_args(&ctx(false, true), netns.clone()),
+            (String::from("doas"), netns.clone())
+        );
+        assert_eq!(
+            privileged_args(&ctx(false, false), wg.clone()),
+            (String::from("doas"), wg)
+        );
+    }
+
+    #[test]
+    fn redact_keys_hides_private_and_preshared_keys() {
+        let conf = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\nListenPort = 51820\n\
+                    [Peer]\nPublicKey = cHVibGlj\nPresharedKey=cHNr\nAllowedIPs = 10.0.0.1/32";
+        assert_eq!(
+            redact_keys(conf),
+            "[Interface]\nPrivateKey = (redacted)\nListenPort = 51820\n\
+             [Peer]\nPublicKey = cHVibGlj\nPresharedKey= (redacted)\nAllowedIPs = 10.0.0.1/32"
+        );
+    }
 }
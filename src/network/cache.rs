@@ -0,0 +1,114 @@
+use super::{
+    peers::now, phonebook::Phonebook, revocation::Revocations, source::Validators, NetworkError,
+};
+use crate::{context::Context, misc::create_private_file, store};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs::{self, DirBuilder},
+    io::Write,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+};
+
+/*
+ * The last phonebook and revocations we curled for a network
+ * Kept in the network's store directory (see store.rs), whether or not the
+ * network was imported, so that:
+ * - the next curl can ask the hub whether it changed (ETag/Last-Modified)
+ * - start still gets its known peers when no hub answers
+ * - revoked keys stay revoked when no hub answers
+ * state.rs keeps its file there with the same helpers.
+ */
+
+pub const PHONEBOOK_CACHE_FILE: &str = "phonebook_cache.json";
+pub const REVOCATIONS_CACHE_FILE: &str = "revocations_cache.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PhonebookCache {
+    pub url: String,
+    #[serde(flatten)]
+    pub validators: Validators,
+    pub fetched: u64,
+    pub phonebook: Phonebook,
+}
+
+impl PhonebookCache {
+    /// The validators to send when curling `url`: only the ones we got from
+    /// that same URL
+    pub fn validators_for(&self, url: &str) -> Validators {
+        if self.url == url {
+            self.validators.clone()
+        } else {
+            Validators::default()
+        }
+    }
+
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.fetched)
+    }
+}
+
+fn cache_path(network_name: &str, file: &str) -> Option<PathBuf> {
+    store::network_dir(network_name)
+        .ok()
+        .map(|dir| dir.join(file))
+}
+
+/// A missing or unreadable cache is no cache
+pub fn read_cache_file<T: DeserializeOwned>(network_name: &str, file: &str) -> Option<T> {
+    let cache_json = fs::read_to_string(cache_path(network_name, file)?).ok()?;
+    serde_json::from_str(&cache_json).ok()
+}
+
+pub fn write_cache_file<T: Serialize>(
+    ctx: &Context,
+    network_name: &str,
+    file: &str,
+    cache: &T,
+) -> Result<(), NetworkError> {
+    let Some(path) = cache_path(network_name, file).filter(|_| !ctx.dry_run) else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let cache_json = serde_json::to_string_pretty(cache)?;
+    let mut file = create_private_file(&path)?;
+    writeln!(file, "{}", cache_json)?;
+    Ok(())
+}
+
+pub fn remove_cache_file(
+    ctx: &Context,
+    network_name: &str,
+    file: &str,
+) -> Result<(), NetworkError> {
+    match cache_path(network_name, file).filter(|_| !ctx.dry_run) {
+        Some(path) if path.exists() => Ok(fs::remove_file(path)?),
+        _ => Ok(()),
+    }
+}
+
+pub fn read_cache(network_name: &str) -> Option<PhonebookCache> {
+    read_cache_file(network_name, PHONEBOOK_CACHE_FILE)
+}
+
+pub fn write_cache(
+    ctx: &Context,
+    network_name: &str,
+    cache: &PhonebookCache,
+) -> Result<(), NetworkError> {
+    write_cache_file(ctx, network_name, PHONEBOOK_CACHE_FILE, cache)
+}
+
+pub fn read_revocations_cache(network_name: &str) -> Option<Revocations> {
+    read_cache_file(network_name, REVOCATIONS_CACHE_FILE)
+}
+
+pub fn write_revocations_cache(
+    ctx: &Context,
+    network_name: &str,
+    revocations: &Revocations,
+) -> Result<(), NetworkError> {
+    write_cache_file(ctx, network_name, REVOCATIONS_CACHE_FILE, revocations)
+}
@@ -0,0 +1,148 @@
+use super::{
+    state::{set_sysctl, IpRule, State},
+    NetworkError,
+};
+use crate::{
+    context::Context,
+    misc::{exec_privileged, exec_privileged_silent, write_staged_file},
+};
+
+/*
+ * Exit-node (full-tunnel) mode
+ * All traffic goes through one PublicEndpoint, whose AllowedIPs then include
+ * 0.0.0.0/0 and ::/0. Like wg-quick, we keep the tunnel's own UDP packets out
+ * of the tunnel with policy routing: the interface marks them with its
+ * fwmark, and everything unmarked is looked up in a routing table (numbered
+ * like the mark) whose default route is the tunnel.
+ * The rules are recorded in the network's State, so that stop only removes
+ * those, except for the `suppress_prefixlength` one, which other networks
+ * (or wg-quick) may be using too, and which is left in place.
+ * The optional kill switch drops any outgoing traffic that is neither going
+ * through the tunnel nor the tunnel's own (marked) packets.
+ */
+
+/*
+ * Above the port numbers wg-quick uses for its marks and tables
+ */
+const FWMARK_BASE: u32 = 0x7475_0000;
+
+/// The fwmark and routing table of an interface, derived from its name (with
+/// FNV-1a, which doesn't change between builds) so that every Tulip network
+/// gets its own
+pub fn fwmark(network_name: &str) -> u32 {
+    let hash = network_name.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    FWMARK_BASE + (hash & 0xffff)
+}
+
+fn kill_switch_table(network_name: &str) -> String {
+    format!("{}_killswitch", network_name)
+}
+
+/// Route everything unmarked through the interface. The rules we add go in
+/// `state` as we go, so that they can be removed even if a later one fails.
+pub fn add_exit_routes(
+    ctx: &Context,
+    network_name: &str,
+    state: &mut State,
+) -> Result<(), NetworkError> {
+    let fwmark = fwmark(network_name).to_string();
+    set_sysctl(ctx, state, "net.ipv4.conf.all.src_valid_mark", "1")?;
+    for (family, default) in [("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+        exec_privileged(
+            ctx,
+            [
+                "ip",
+                family,
+                "route",
+                "add",
+                default,
+                "dev",
+                network_name,
+                "table",
+                &fwmark,
+            ],
+        )?;
+        let not_marked = IpRule {
+            family: family.to_string(),
+            selector: ["not", "fwmark", &fwmark, "table", &fwmark]
+                .map(String::from)
+                .to_vec(),
+        };
+        exec_privileged(ctx, rule_args(&not_marked, "add"))?;
+        state.rules.push(not_marked);
+        /*
+         * There's only one such rule (adding it again fails), shared with
+         * wg-quick and the other Tulip networks, so it's never recorded: it
+         * stays after stop, where it's harmless without the rules above
+         */
+        let _ = exec_privileged_silent(
+            ctx,
+            [
+                "ip",
+                family,
+                "rule",
+                "add",
+                "table",
+                "main",
+                "suppress_prefixlength",
+                "0",
+            ],
+        );
+    }
+    Ok(())
+}
+
+fn rule_args(rule: &IpRule, action: &str) -> Vec<String> {
+    ["ip", &rule.family, "rule", action]
+        .map(String::from)
+        .into_iter()
+        .chain(rule.selector.iter().cloned())
+        .collect()
+}
+
+pub fn kill_switch_ruleset(network_name: &str) -> String {
+    format!(
+        "table inet {table} {{
+    chain output {{
+        type filter hook output priority 0; policy accept;
+        oifname \"lo\" accept
+        oifname \"{network_name}\" accept
+        meta mark {fwmark} accept
+        reject
+    }}
+}}
+",
+        table = kill_switch_table(network_name),
+        fwmark = fwmark(network_name),
+    )
+}
+
+pub fn add_kill_switch(ctx: &Context, network_name: &str) -> Result<(), NetworkError> {
+    let path = format!("/tmp/{}_killswitch.nft", network_name);
+    write_staged_file(ctx, &path, &kill_switch_ruleset(network_name))?;
+    exec_privileged(ctx, ["nft", "-f", &path])?;
+    Ok(())
+}
+
+/// Undo add_exit_routes (the rules recorded in `state`) and add_kill_switch.
+/// The routes themselves go away with the interface; the rules and the
+/// nftables table don't.
+/// Errors are ignored, since the network may not have been in exit-node mode.
+pub fn remove(ctx: &Context, network_name: &str, state: &State) -> Result<(), NetworkError> {
+    for rule in state.rules.iter() {
+        let _ = exec_privileged_silent(ctx, rule_args(rule, "del"));
+    }
+    let _ = exec_privileged_silent(
+        ctx,
+        [
+            "nft",
+            "delete",
+            "table",
+            "inet",
+            &kill_switch_table(network_name),
+        ],
+    );
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use super::{
+    peers::{allowed_ips, set_allowed_ips, wait_for_handshakes},
+    Network, NetworkError, PublicEndpoint,
+};
+use crate::context::Context;
+
+/*
+ * Active/standby hubs
+ * WireGuard routes a prefix through exactly one peer, so only one
+ * PublicEndpoint (the active hub) carries the network's subnet. The others
+ * are standbys, and every hub keeps its own /32 either way.
+ * The first PublicEndpoint listed is the primary: it's active whenever it's
+ * up, otherwise the next one up (in order) takes over.
+ */
+
+pub const FULL_TUNNEL: [&str; 2] = ["0.0.0.0/0", "::/0"];
+
+fn hub_ip(hub: &PublicEndpoint) -> String {
+    format!("{}/32", hub.vpn_ip)
+}
+
+/// The hub currently carrying the subnet on the running interface, if any
+pub fn active_hub<'a>(
+    ctx: &Context,
+    network: &'a Network,
+    network_name: &str,
+) -> Result<Option<&'a PublicEndpoint>, NetworkError> {
+    let allowed_ips = allowed_ips(ctx, network_name)?;
+    Ok(network.public_endpoints.iter().find(|hub| {
+        allowed_ips
+            .get(&hub.public_key)
+            .is_some_and(|ips| ips.contains(&network.subnet))
+    }))
+}
+
+/// Move the subnet, and whatever else the active hub routes for us (e.g. the
+/// LAN routes of users we can't reach directly in mesh mode), to `hub`.
+/// The old hub keeps its /32, and its default routes if it's the exit node.
+pub fn activate(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    hub: &PublicEndpoint,
+) -> Result<(), NetworkError> {
+    let mut allowed_ips = allowed_ips(ctx, network_name)?;
+    let mut hub_allowed_ips = allowed_ips.remove(&hub.public_key).unwrap_or_default();
+    let old_hub = active_hub(ctx, network, network_name)?;
+    let carried = match old_hub {
+        Some(old_hub) => allowed_ips
+            .remove(&old_hub.public_key)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|ip| *ip != hub_ip(old_hub) && !FULL_TUNNEL.contains(&ip.as_str()))
+            .collect(),
+        None => vec![network.subnet.clone()],
+    };
+    for ip in carried {
+        if !hub_allowed_ips.contains(&ip) {
+            hub_allowed_ips.push(ip);
+        }
+    }
+    /*
+     * WireGuard takes each prefix away from the old hub as the new one gets it
+     */
+    set_allowed_ips(ctx, network_name, &hub.public_key, &hub_allowed_ips)?;
+    Ok(())
+}
+
+/// Make the first of the `up` hubs active, in the network's order
+pub fn activate_first(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    up: &[&PublicEndpoint],
+) -> Result<(), NetworkError> {
+    let Some(preferred) = network
+        .public_endpoints
+        .iter()
+        .find(|hub| up.iter().any(|up| up.public_key == hub.public_key))
+    else {
+        return Ok(());
+    };
+    let active = match active_hub(ctx, network, network_name) {
+        /*
+         * In a dry run of start, the interface doesn't exist yet
+         */
+        Err(_) if ctx.dry_run => return Ok(()),
+        active => active?,
+    };
+    match active {
+        Some(active) if active.public_key == preferred.public_key => Ok(()),
+        None if ctx.dry_run => Ok(()),
+        active => {
+            ctx.report.say(&format!(
+                "routing {} through {} instead of {}",
+                &network.subnet,
+                &preferred.name,
+                active.map(|hub| hub.name.as_str()).unwrap_or("nothing")
+            ));
+            activate(ctx, network, network_name, preferred)
+        }
+    }
+}
+
+/// Probe every hub, wait up to `timeout` seconds for their handshakes, and
+/// fail over to (or back to) the first healthy one.
+/// If no hub is up, leave things as they are.
+pub fn check_hubs(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    timeout: u64,
+) -> Result<(), NetworkError> {
+    let peers: Vec<(&str, &str)> = network
+        .public_endpoints
+        .iter()
+        .map(|hub| (hub.public_key.as_str(), hub.vpn_ip.as_str()))
+        .collect();
+    let handshaken = wait_for_handshakes(ctx, network_name, &peers, timeout)?;
+    let up: Vec<&PublicEndpoint> = network
+        .public_endpoints
+        .iter()
+        .filter(|hub| handshaken.contains(&hub.public_key))
+        .collect();
+    activate_first(ctx, network, network_name, &up)
+}
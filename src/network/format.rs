@@ -0,0 +1,95 @@
+use super::NetworkError;
+use crate::{context::Context, misc::create_private_file};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fs, io::Write, path::Path};
+
+/*
+ * File formats for tulip_network and phonebook files
+ * JSON, TOML or YAML, picked by the file's extension (.json, .toml,
+ * .yaml/.yml), or by the Context's format (--format) for files whose
+ * extension doesn't say, JSON being the default. They all parse into the
+ * same structs, and a file tulip rewrites (e.g. the phonebook on revoke)
+ * keeps its format, though not its comments.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+pub const FORMATS: [&str; 3] = ["json", "toml", "yaml"];
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    /// The format of the file at `path`, `default` if its extension doesn't
+    /// say
+    pub fn of<P: AsRef<Path>>(path: P, default: Option<Format>) -> Format {
+        path.as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Format::from_name)
+            .or(default)
+            .unwrap_or(Format::Json)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, contents: &str) -> Result<T, NetworkError> {
+        Ok(match self {
+            Format::Json => serde_json::from_str(contents)?,
+            Format::Toml => toml::from_str(contents)?,
+            Format::Yaml => serde_yaml::from_str(contents)?,
+        })
+    }
+
+    pub fn to_string<T: Serialize>(&self, value: &T) -> Result<String, NetworkError> {
+        Ok(match self {
+            Format::Json => serde_json::to_string_pretty(value)?,
+            Format::Toml => toml::to_string_pretty(value)?,
+            Format::Yaml => serde_yaml::to_string(value)?,
+        })
+    }
+}
+
+pub fn read_file<T: DeserializeOwned, P: AsRef<Path>>(
+    ctx: &Context,
+    path: P,
+) -> Result<T, NetworkError> {
+    let contents = fs::read_to_string(&path)?;
+    Format::of(&path, ctx.format).parse(&contents)
+}
+
+pub fn write_file<T: Serialize, P: AsRef<Path>>(
+    ctx: &Context,
+    path: P,
+    value: &T,
+) -> Result<(), NetworkError> {
+    write_file_as(Format::of(&path, ctx.format), path, value)
+}
+
+pub fn write_file_as<T: Serialize, P: AsRef<Path>>(
+    format: Format,
+    path: P,
+    value: &T,
+) -> Result<(), NetworkError> {
+    let contents = format.to_string(value)?;
+    let mut file = create_private_file(&path)?;
+    writeln!(file, "{}", contents.trim_end())?;
+    Ok(())
+}
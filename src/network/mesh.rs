@@ -0,0 +1,157 @@
+use super::{
+    failover::active_hub,
+    peers::{
+        allowed_ips, is_fresh, latest_handshakes, probe, remove_peer, set_allowed_ips, KEEPALIVE,
+    },
+    phonebook::Phonebook,
+    Network, NetworkError, PrivateEndpoint,
+};
+use crate::{context::Context, misc::exec_privileged};
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/*
+ * Full-mesh mode
+ * Instead of reaching other users through a PublicEndpoint, peer directly with
+ * those whose phonebook entry publishes reachable `endpoints` (or, in a dynamic
+ * phonebook, an `observed_endpoint`), trying each one in turn until a
+ * handshake succeeds.
+ * Users we can't reach directly are left to the hub: they're removed from the
+ * interface, and the LAN routes they advertise are handed to the active hub
+ * (see failover.rs).
+ */
+
+fn direct_allowed_ips(user: &PrivateEndpoint) -> Vec<String> {
+    [format!("{}/32", user.vpn_ip)]
+        .into_iter()
+        .chain(user.routes.iter().cloned())
+        .collect()
+}
+
+/*
+ * The endpoints a user publishes, then the one the hub last saw them at
+ */
+fn candidates(user: &PrivateEndpoint) -> Vec<&str> {
+    let mut candidates: Vec<&str> = user.endpoints.iter().map(String::as_str).collect();
+    if let Some(observed) = &user.observed_endpoint {
+        if !candidates.contains(&observed.as_str()) {
+            candidates.push(observed);
+        }
+    }
+    candidates
+}
+
+fn set_direct_peer(
+    ctx: &Context,
+    network_name: &str,
+    user: &PrivateEndpoint,
+    endpoint: &str,
+) -> Result<(), NetworkError> {
+    exec_privileged(
+        ctx,
+        [
+            "wg",
+            "set",
+            network_name,
+            "peer",
+            &user.public_key,
+            "endpoint",
+            endpoint,
+            "allowed-ips",
+            &direct_allowed_ips(user).join(","),
+            "persistent-keepalive",
+            &KEEPALIVE.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+/*
+ * Hand the LAN routes of the users we couldn't reach to the hub
+ */
+fn fall_back_to_hub(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    unreachable: &[&PrivateEndpoint],
+) -> Result<(), NetworkError> {
+    for user in unreachable.iter() {
+        if !candidates(user).is_empty() {
+            ctx.report.say(&format!(
+                "no direct handshake with {}, going through the hub",
+                &user.name
+            ));
+        }
+        remove_peer(ctx, network_name, &user.public_key)?;
+    }
+    let routes: Vec<String> = unreachable
+        .iter()
+        .flat_map(|user| user.routes.iter().cloned())
+        .collect();
+    let hub = active_hub(ctx, network, network_name)?.or(network.public_endpoints.first());
+    if let (Some(hub), false) = (hub, routes.is_empty()) {
+        let mut hub_allowed_ips = allowed_ips(ctx, network_name)?
+            .remove(&hub.public_key)
+            .unwrap_or_default();
+        for route in routes {
+            if !hub_allowed_ips.contains(&route) {
+                hub_allowed_ips.push(route);
+            }
+        }
+        set_allowed_ips(ctx, network_name, &hub.public_key, &hub_allowed_ips)?;
+    }
+    Ok(())
+}
+
+/// Peer directly with every reachable user of the phonebook, waiting up to
+/// `timeout` seconds for a handshake on each of their endpoints.
+/// Users we already have a live session with, and hubs, are left alone.
+pub fn add_direct_peers(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    phonebook: &Phonebook,
+    timeout: u64,
+) -> Result<(), NetworkError> {
+    let handshakes = latest_handshakes(ctx, network_name)?;
+    let mut pending: Vec<&PrivateEndpoint> = phonebook
+        .values()
+        .filter(|user| user.name != network.user.name)
+        // a hub listed in the phonebook is already a peer, with the subnet
+        .filter(|user| {
+            !network
+                .public_endpoints
+                .iter()
+                .any(|hub| hub.public_key == user.public_key)
+        })
+        .filter(|user| !is_fresh(handshakes.get(&user.public_key).copied().unwrap_or(0)))
+        .collect();
+    let mut unreachable: Vec<&PrivateEndpoint> = vec![];
+    let mut attempt = 0;
+    while !pending.is_empty() {
+        let (trying, exhausted): (Vec<&PrivateEndpoint>, Vec<&PrivateEndpoint>) = pending
+            .into_iter()
+            .partition(|user| attempt < candidates(user).len());
+        unreachable.extend(exhausted);
+        for user in trying.iter() {
+            set_direct_peer(ctx, network_name, user, candidates(user)[attempt])?;
+            probe(ctx, &user.vpn_ip);
+        }
+        /*
+         * A dry run doesn't wait: no attempt gets a handshake, so it shows
+         * every endpoint we'd try, then the fallback to the hub
+         */
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        pending = trying;
+        while !ctx.dry_run && !pending.is_empty() && Instant::now() < deadline {
+            sleep(Duration::from_millis(250));
+            let handshakes = latest_handshakes(ctx, network_name)?;
+            pending
+                .retain(|user| !is_fresh(handshakes.get(&user.public_key).copied().unwrap_or(0)));
+        }
+        attempt += 1;
+    }
+    fall_back_to_hub(ctx, network, network_name, &unreachable)
+}
@@ -1,32 +1,91 @@
 use self::{
-    phonebook::{curl_phonebook_list, Phonebook},
+    mtu::Mtu,
+    peers::wait_for_handshakes,
+    phonebook::{
+        check_routes, curl_phonebook_from, curl_phonebook_list, write_dynamic_phonebook_file,
+        Phonebook,
+    },
+    policy::PolicyRule,
+    revocation::{
+        curl_revocations_from, curl_revocations_list, evict, without_revoked, Revocations,
+    },
+    source::{EndpointErrors, PhonebookSource},
+    state::{read_state, remove_state, restore_sysctls, set_sysctl, write_state, State},
+    visibility::VisibilityRule,
     wg_conf::{get_wg_interface_name, NetworkWgConfInput, WgConfSection},
 };
 use crate::{
+    context::Context,
     id::PrivId,
-    misc::{countdown, create_private_file, exec},
+    misc::{self, exec_privileged, exec_privileged_in_init_netns, write_staged_file},
+    report::PeerReport,
+    schema,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{
-    fs::{self},
-    io::Write,
-};
+use std::{collections::HashMap, path::Path};
+pub mod cache;
+pub mod exit_node;
+pub mod failover;
+pub mod format;
+pub mod mesh;
+pub mod mtu;
+pub mod peers;
 pub mod phonebook;
+pub mod policy;
+pub mod revocation;
+pub mod source;
+pub mod state;
+pub mod sync;
+pub mod visibility;
 pub mod wg_conf;
 
-/*
- * Error enum
- */
+/// Error enum
 #[derive(Debug)]
 pub enum NetworkError {
-    // BadSubnet(String),
+    BadPolicy(String),
+    BadSource(String),
+    BadSubnet(String),
     CurlsFailed(String),
     FileIO(std::io::Error),
     MissingPort(String),
+    NoNetns(String),
+    NoHandshake(u64),
+    NoInterface(String),
     Serde(serde_json::Error),
-    Ureq(ureq::Error),
+    TomlDe(toml::de::Error),
+    TomlSer(toml::ser::Error),
+    UnknownEndpoint(String),
+    Ureq(Box<ureq::Error>),
+    Yaml(serde_yaml::Error),
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::BadPolicy(msg) => write!(f, "bad policy: {msg}"),
+            NetworkError::BadSource(msg) => write!(f, "bad phonebook source: {msg}"),
+            NetworkError::BadSubnet(msg) => write!(f, "bad subnet: {msg}"),
+            NetworkError::CurlsFailed(msg) => write!(f, "{msg}"),
+            NetworkError::FileIO(e) => write!(f, "file error: {e}"),
+            NetworkError::MissingPort(msg) => write!(f, "{msg}"),
+            NetworkError::NoNetns(name) => write!(f, "no network namespace named {name}"),
+            NetworkError::NoHandshake(timeout) => {
+                write!(f, "no WireGuard handshake within {timeout}s")
+            }
+            NetworkError::NoInterface(name) => write!(f, "no WireGuard interface named {name}"),
+            NetworkError::Serde(e) => write!(f, "json error: {e}"),
+            NetworkError::TomlDe(e) => write!(f, "toml error: {e}"),
+            NetworkError::TomlSer(e) => write!(f, "toml error: {e}"),
+            NetworkError::UnknownEndpoint(name) => write!(f, "no public endpoint named {name}"),
+            NetworkError::Ureq(e) => write!(f, "http error: {e}"),
+            NetworkError::Yaml(e) => write!(f, "yaml error: {e}"),
+        }
+    }
 }
 
+impl std::error::Error for NetworkError {}
+
 impl From<std::io::Error> for NetworkError {
     fn from(e: std::io::Error) -> Self {
         NetworkError::FileIO(e)
@@ -35,7 +94,7 @@ impl From<std::io::Error> for NetworkError {
 
 impl From<ureq::Error> for NetworkError {
     fn from(e: ureq::Error) -> Self {
-        NetworkError::Ureq(e)
+        NetworkError::Ureq(Box::new(e))
     }
 }
 
@@ -45,108 +104,363 @@ impl From<serde_json::Error> for NetworkError {
     }
 }
 
+impl From<toml::de::Error> for NetworkError {
+    fn from(e: toml::de::Error) -> Self {
+        NetworkError::TomlDe(e)
+    }
+}
+
+impl From<toml::ser::Error> for NetworkError {
+    fn from(e: toml::ser::Error) -> Self {
+        NetworkError::TomlSer(e)
+    }
+}
+
+impl From<serde_yaml::Error> for NetworkError {
+    fn from(e: serde_yaml::Error) -> Self {
+        NetworkError::Yaml(e)
+    }
+}
+
 /*
  * The different types of endpoints available in our JSON config files.
  * - PublicEndpoint: a Tulip network endpoint with a Wireguard interface accessible
- *    from the public Internet.
+ *    from the public Internet. It may say where it serves the phonebook (see source.rs).
  * - PrivateEndpoint: a Tulip network endpoint only available via the network's internal phonebook.json.
+ *    It may act as a gateway for the LAN `routes` behind it, and may publish
+ *    `endpoints` (host:port) where other users can reach it directly.
+ *    In a dynamic phonebook, the hub also fills in the `observed_endpoint` it
+ *    last saw the user at, and the time of their `last_handshake`.
  * - UserEndpoint: YOU, the user joining a Tulip network via WireGuard, using a private key.
  */
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct PublicEndpoint {
+    /// Name of the endpoint, e.g. for --exit-node
     pub name: String,
+    /// Its IP address inside the network
+    #[schemars(schema_with = "schema::ipv4")]
     pub vpn_ip: String,
+    /// Hostname or IP address WireGuard reaches it at
     pub public_hostname: String,
+    /// Its WireGuard public key
+    #[schemars(schema_with = "schema::wg_key")]
     pub public_key: String,
+    /// Its WireGuard UDP port
+    #[schemars(range(min = 1, max = 65535))]
     pub port: i64,
+    /// Where it serves phonebook.json, if not http://<vpn_ip>/phonebook.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phonebook: Option<PhonebookSource>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct PrivateEndpoint {
+    /// Name of the user
     pub name: String,
+    /// Their IP address inside the network
+    #[schemars(schema_with = "schema::ipv4")]
     pub vpn_ip: String,
+    /// Their WireGuard public key
+    #[schemars(schema_with = "schema::wg_key")]
     pub public_key: String,
+    /// LAN subnets (CIDR) they're a gateway for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(schema_with = "schema::cidrs")]
+    pub routes: Vec<String>,
+    /// Groups they're in, for the network's policy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Where (host:port) other users can reach them directly, in mesh mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(schema_with = "schema::host_ports")]
+    pub endpoints: Vec<String>,
+    /// Where (host:port) the hub last saw them, in a dynamic phonebook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "schema::host_port")]
+    pub observed_endpoint: Option<String>,
+    /// When (Unix time) the hub last had a handshake with them, in a dynamic
+    /// phonebook
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_handshake: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct UserEndpoint {
+    /// Your name in the phonebook
     pub name: String,
+    /// Your IP address inside the network
+    #[schemars(schema_with = "schema::ipv4")]
     pub vpn_ip: String,
+    /// WireGuard UDP port to listen on (required in server mode)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1, max = 65535))]
     pub port: Option<u64>,
+    /// Your interface's MTU, overriding the network's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<Mtu>,
 }
 
-/*
- * The main .*_tulip_network.json format
- */
-#[derive(Serialize, Deserialize, Debug)]
+/// The main .*_tulip_network.json format
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct Network {
+    /// Name of the network (its first 8 characters name the interface)
     pub name: String,
+    /// The network's subnet (CIDR)
+    #[schemars(schema_with = "schema::cidr")]
     pub subnet: String,
+    /// You
     pub user: UserEndpoint,
+    /// The hubs, the first one being the primary
     pub public_endpoints: Vec<PublicEndpoint>,
+    /// Where the hubs serve phonebook.json, unless they say otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phonebook: Option<PhonebookSource>,
+    /// The interface's MTU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<Mtu>,
+    /// Network namespace to put the interface in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+    /// Which groups of users may reach which, enforced by the hubs. Without
+    /// it, everyone can reach everyone
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policy: Vec<PolicyRule>,
+    /// Which groups of users see which in the phonebook the hubs serve.
+    /// Without it, everyone sees everyone
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub visibility: Vec<VisibilityRule>,
 }
 
-pub fn read_network_file(path: &str) -> Result<Network, NetworkError> {
-    let network_json = fs::read_to_string(path)?;
-    let res = serde_json::from_str(&network_json)?;
-    Ok(res)
+impl Network {
+    pub fn public_endpoint(&self, name: &str) -> Result<&PublicEndpoint, NetworkError> {
+        self.public_endpoints
+            .iter()
+            .find(|endpoint| endpoint.name == name)
+            .ok_or(NetworkError::UnknownEndpoint(name.to_string()))
+    }
+
+    /// Where to fetch the phonebook from `endpoint`: its own source, else the
+    /// network's, else the default http://<vpn_ip>/phonebook.json
+    pub fn phonebook_source(&self, endpoint: &PublicEndpoint) -> PhonebookSource {
+        endpoint
+            .phonebook
+            .clone()
+            .or_else(|| self.phonebook.clone())
+            .unwrap_or_default()
+    }
 }
 
-/*
- * start(network, priv_id, server)
- * -------------------------------
- * Start the Tulip `Network`
- * Create /tmp/example_tulip_network.conf and give it to wg-quick.
- * If starting in server mode, set appropriate kernel parameters.
- * Then pause for a moment and curl the phonebook.json from the first
- * available PublicEndpoints, in order.
- */
+pub fn read_network_file(ctx: &Context, path: &str) -> Result<Network, NetworkError> {
+    format::read_file(ctx, path)
+}
+
+pub fn write_network_file<P: AsRef<Path>>(
+    ctx: &Context,
+    path: P,
+    network: &Network,
+) -> Result<(), NetworkError> {
+    format::write_file(ctx, path, network)
+}
+
+/// How to start a Tulip `Network`
+/// - server: start in server mode
+/// - timeout: timeout for querying the phonebook (seconds)
+/// - exit_node: name of a PublicEndpoint to route all traffic through
+/// - kill_switch: in exit-node mode, block traffic outside the tunnel
+/// - mesh: peer directly with users that publish endpoints (see mesh.rs)
+/// - dynamic_phonebook: in server mode, where to publish the phonebook along
+///   with what the hub observes of its users (see write_dynamic_phonebook_file)
+#[derive(Debug, Default)]
+pub struct StartInput {
+    pub server: bool,
+    pub timeout: u64,
+    pub exit_node: Option<String>,
+    pub kill_switch: bool,
+    pub mesh: bool,
+    pub dynamic_phonebook: Option<String>,
+}
+
+/// Start the Tulip `Network`
+///
+/// Create /tmp/example_tulip_network.conf and give it to wg-quick.
+/// If starting in server mode, turn on forwarding (until stop).
+/// Then wait for a handshake with the PublicEndpoints and curl the
+/// phonebook.json (and revocations.json) from those that answered.
+/// Users whose keys were revoked are left out.
 pub fn start(
+    ctx: &Context,
     network: Network,
     priv_id: PrivId,
-    server: bool,
     phonebook: Option<Phonebook>,
-    timeout: u64,
+    revocations: Option<Revocations>,
+    input: &StartInput,
 ) -> Result<(), NetworkError> {
-    if server && (network.user.port.is_none() || phonebook.is_none()) {
+    let ctx = &prepare(ctx, &network)?;
+    check_netns(&network)?;
+    if input.server && (network.user.port.is_none() || phonebook.is_none()) {
         Err(NetworkError::MissingPort(String::from(
             "in --server mode, you need a port",
         )))
+    } else if !input.server && input.dynamic_phonebook.is_some() {
+        Err(NetworkError::MissingPort(String::from(
+            "a dynamic phonebook can only be published in --server mode",
+        )))
     } else {
-        add_wg_interface(&network, priv_id, phonebook, timeout)?;
+        if let Some(exit_node) = &input.exit_node {
+            network.public_endpoint(exit_node)?;
+        }
+        if input.server {
+            policy::check(&network)?;
+        }
+        add_wg_interface(ctx, &network, priv_id, phonebook, revocations, input)?;
+        let network_name = get_wg_interface_name(&network.name);
+        ctx.report.interface(&network_name);
+        /*
+         * In a dry run, nothing was configured
+         */
+        if ctx.report.json() && !ctx.dry_run {
+            ctx.report
+                .peers(peer_reports(ctx, &network, &network_name)?);
+        }
         Ok(())
     }
 }
 
+/// Stop the Tulip `Network`
+///
+/// Also undo what start changed outside the interface (see state.rs), such as
+/// a hub's forwarding kernel parameters.
+pub fn stop(ctx: &Context, network: Network) -> Result<(), NetworkError> {
+    let ctx = &prepare(ctx, &network)?;
+    let network_name = get_wg_interface_name(&network.name);
+    /*
+     * The rules and tables outlive the interface, so they go even if it's
+     * already gone
+     */
+    let state = read_state(&network.name);
+    exit_node::remove(ctx, &network_name, &state)?;
+    policy::remove(ctx, &network_name)?;
+    restore_sysctls(ctx, &state)?;
+    remove_state(ctx, &network.name)?;
+    ctx.report.interface(&network_name);
+    exec_privileged(ctx, ["ip", "link", "delete", "dev", &network_name])?;
+    Ok(())
+}
+
+/// Show the Tulip `Network`'s interface: its addresses and WireGuard peers
+///
+/// With --json, report its peers instead
+pub fn status(ctx: &Context, network: &Network) -> Result<(), NetworkError> {
+    let ctx = &prepare(ctx, network)?;
+    let network_name = get_wg_interface_name(&network.name);
+    ctx.report.interface(&network_name);
+    if ctx.report.json() {
+        ctx.report.peers(peer_reports(ctx, network, &network_name)?);
+        return Ok(());
+    }
+    match &network.netns {
+        Some(netns) => ctx.report.say(&format!(
+            "{} ({} in netns {})",
+            &network.name, &network_name, netns
+        )),
+        None => ctx
+            .report
+            .say(&format!("{} ({})", &network.name, &network_name)),
+    }
+    exec_privileged(
+        ctx,
+        ["ip", "-brief", "address", "show", "dev", &network_name],
+    )?;
+    exec_privileged(ctx, ["wg", "show", &network_name])?;
+    Ok(())
+}
+
 /*
- * stop(network, server)
- * -------------------------------
- * Stop the Tulip `Network`
- * If stopping in server mode, set appropriate kernel parameters.
+ * The running interface's peers, named after the network's PublicEndpoints
+ * and the last phonebook we curled
  */
-pub fn stop(network: Network) -> Result<(), NetworkError> {
-    let network_name = format!("tulip_{}", &network.name[..8]);
-    exec("sudo", ["ip", "link", "delete", "dev", &network_name])?;
-    Ok(())
+fn peer_reports(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+) -> Result<Vec<PeerReport>, NetworkError> {
+    let mut names: HashMap<String, String> = cache::read_cache(&network.name)
+        .map(|cache| {
+            cache
+                .phonebook
+                .into_values()
+                .map(|user| (user.public_key, user.name))
+                .collect()
+        })
+        .unwrap_or_default();
+    for hub in &network.public_endpoints {
+        names.insert(hub.public_key.clone(), hub.name.clone());
+    }
+    let endpoints = peers::endpoints(ctx, network_name)?;
+    let latest_handshakes = peers::latest_handshakes(ctx, network_name)?;
+    let mut reports: Vec<PeerReport> = peers::allowed_ips(ctx, network_name)?
+        .into_iter()
+        .map(|(public_key, allowed_ips)| PeerReport {
+            name: names.get(&public_key).cloned(),
+            endpoint: endpoints.get(&public_key).cloned(),
+            latest_handshake: latest_handshakes
+                .get(&public_key)
+                .copied()
+                .filter(|t| *t > 0),
+            public_key,
+            allowed_ips,
+        })
+        .collect();
+    reports.sort_by(|a, b| a.name.cmp(&b.name).then(a.public_key.cmp(&b.public_key)));
+    Ok(reports)
+}
+
+/*
+ * Run the networking commands in the `Network`'s namespace, if any, and make
+ * sure we can run them at all
+ */
+fn prepare(ctx: &Context, network: &Network) -> Result<Context, NetworkError> {
+    let ctx = ctx.with_netns(network.netns.clone());
+    misc::check_privileges(&ctx, false)?;
+    Ok(ctx)
+}
+
+/// Make sure the network namespace we're asked to use exists, since
+/// `ip link set netns` would otherwise leave the interface where it is
+pub fn check_netns(network: &Network) -> Result<(), NetworkError> {
+    match &network.netns {
+        Some(netns) if !Path::new("/run/netns").join(netns).exists() => {
+            Err(NetworkError::NoNetns(netns.clone()))
+        }
+        _ => Ok(()),
+    }
 }
 
 fn add_wg_interface(
+    ctx: &Context,
     network: &Network,
     priv_id: PrivId,
     phonebook: Option<Phonebook>,
-    timeout: u64,
+    revocations: Option<Revocations>,
+    input: &StartInput,
 ) -> Result<(), NetworkError> {
+    let timeout = input.timeout;
     let network_name = get_wg_interface_name(&network.name);
     /*
      * Create wg interface and set some of its basic properties
+     * In a network namespace, the interface is created outside of it and then
+     * moved in, so that its UDP socket stays in the init namespace
      */
-    exec(
-        "sudo",
+    exec_privileged_in_init_netns(
+        ctx,
         ["ip", "link", "add", &network_name, "type", "wireguard"],
     )?;
-    exec(
-        "sudo",
+    if let Some(netns) = &network.netns {
+        exec_privileged_in_init_netns(ctx, ["ip", "link", "set", &network_name, "netns", netns])?;
+    }
+    exec_privileged(
+        ctx,
         [
             "ip",
             "-4",
@@ -157,13 +471,21 @@ fn add_wg_interface(
             &network_name,
         ],
     )?;
-    exec(
-        "sudo",
-        ["ip", "link", "set", "mtu", "1420", "dev", &network_name],
+    exec_privileged(
+        ctx,
+        [
+            "ip",
+            "link",
+            "set",
+            "mtu",
+            &mtu::interface_mtu(ctx, network).to_string(),
+            "dev",
+            &network_name,
+        ],
     )?;
-    exec("sudo", ["ip", "link", "set", "up", "dev", &network_name])?;
-    exec(
-        "sudo",
+    exec_privileged(ctx, ["ip", "link", "set", "up", "dev", &network_name])?;
+    exec_privileged(
+        ctx,
         [
             "ip",
             "-4",
@@ -174,36 +496,232 @@ fn add_wg_interface(
             &network_name,
         ],
     )?;
+    /*
+     * Hubs forward their users' traffic. Forwarding stays on until stop puts
+     * it back the way it was.
+     */
+    let mut state = State::default();
+    if input.server {
+        for key in ["net.ipv4.ip_forward", "net.ipv6.conf.all.forwarding"] {
+            let set = set_sysctl(ctx, &mut state, key, "1");
+            write_state(ctx, &network.name, &state)?;
+            set?;
+        }
+    }
     let path = format!("/tmp/{}.conf", &network_name);
-    let mut wg_conf = create_private_file(&path)?;
     /*
      * Add public endpoints to the WireGuard config
      */
-    writeln!(
-        wg_conf,
-        "{}",
-        network.wg_conf_section(&NetworkWgConfInput {
-            priv_id,
-            mobile: false,
-            port: network.user.port
-        })
-    )?;
-    exec("sudo", ["wg", "setconf", &network_name, &path])?;
+    let wg_conf = network.wg_conf_section(&NetworkWgConfInput {
+        priv_id,
+        mobile: false,
+        port: network.user.port,
+        exit_node: input.exit_node.clone(),
+    });
+    write_staged_file(ctx, &path, &format!("{}\n", wg_conf))?;
+    exec_privileged(ctx, ["wg", "setconf", &network_name, &path])?;
+    if input.exit_node.is_some() {
+        let added = exit_node::add_exit_routes(ctx, &network_name, &mut state);
+        write_state(ctx, &network.name, &state)?;
+        added?;
+        if input.kill_switch {
+            exit_node::add_kill_switch(ctx, &network_name)?;
+        }
+    }
     /*
      * Add phonebook users to the WireGuard config
      * If in server mode, the `phonebook` arg here will be Some
-     * Otherwise, it will be none and curled from a WireGuard endpoint
+     * Otherwise, it will be none and curled from the WireGuard endpoints we
+     * managed to handshake with
      */
+    let (phonebook, revocations) = match phonebook {
+        Some(p) => (
+            p,
+            match revocations {
+                Some(r) => r,
+                None => curl_revocations_list(ctx, network, timeout)?,
+            },
+        ),
+        None => {
+            let (up, down) = handshaken_public_endpoints(ctx, network, &network_name, timeout)?;
+            failover::activate_first(ctx, network, &network_name, &up)?;
+            let phonebook = curl_phonebook_from(ctx, network, &up, down, timeout)?;
+            let revocations = match revocations {
+                Some(r) => r,
+                None => curl_revocations_from(ctx, network, &up, timeout)?,
+            };
+            (phonebook, revocations)
+        }
+    };
+    let phonebook = without_revoked(ctx, phonebook, &revocations);
+    add_phonebook_peers(ctx, network, &network_name, &phonebook, input.mesh, timeout)?;
+    if input.server {
+        policy::apply(ctx, network, &network_name, &phonebook)?;
+    }
+    if let Some(path) = &input.dynamic_phonebook {
+        write_dynamic_phonebook_file(ctx, path, &network_name, &phonebook)?;
+    }
+    Ok(())
+}
+
+/*
+ * Wait up to `timeout` seconds for a handshake with each PublicEndpoint.
+ * Returns those that answered, and those that didn't.
+ */
+fn handshaken_public_endpoints<'a>(
+    ctx: &Context,
+    network: &'a Network,
+    network_name: &str,
+    timeout: u64,
+) -> Result<(Vec<&'a PublicEndpoint>, EndpointErrors), NetworkError> {
+    let peers: Vec<(&str, &str)> = network
+        .public_endpoints
+        .iter()
+        .map(|endpoint| (endpoint.public_key.as_str(), endpoint.vpn_ip.as_str()))
+        .collect();
+    let handshaken = wait_for_handshakes(ctx, network_name, &peers, timeout)?;
+    let (up, down): (Vec<&PublicEndpoint>, Vec<&PublicEndpoint>) = network
+        .public_endpoints
+        .iter()
+        .partition(|endpoint| handshaken.contains(&endpoint.public_key));
+    let down = down
+        .into_iter()
+        .map(|endpoint| (endpoint.name.clone(), NetworkError::NoHandshake(timeout)))
+        .collect();
+    Ok((up, down))
+}
+
+/// Re-read the phonebook of a running Tulip `Network` and add any new or
+/// changed users to its WireGuard interface, evicting revoked ones.
+/// In server mode the `phonebook` and `revocations` args will be Some;
+/// otherwise they are curled from the network's PublicEndpoints.
+/// In client mode, the subnet is moved to the first healthy hub first; in
+/// server mode, the network's policy is rebuilt for the new phonebook.
+/// In mesh mode, users we lost our direct session with are retried.
+/// If given a `dynamic_phonebook` path, republish it with fresh observations.
+pub fn refresh(
+    ctx: &Context,
+    network: &Network,
+    phonebook: Option<Phonebook>,
+    revocations: Option<Revocations>,
+    mesh: bool,
+    timeout: u64,
+    dynamic_phonebook: Option<&str>,
+) -> Result<(), NetworkError> {
+    let ctx = &prepare(ctx, network)?;
+    let network_name = get_wg_interface_name(&network.name);
+    let server = phonebook.is_some();
     let phonebook = match phonebook {
-        Some(p) => Ok(p),
+        Some(p) => p,
         None => {
-            countdown(3)?;
-            curl_phonebook_list(&network.public_endpoints, timeout)
+            failover::check_hubs(ctx, network, &network_name, timeout)?;
+            curl_phonebook_list(ctx, network, timeout)?
         }
-    }?;
-    let path = format!("/tmp/{}_phonebook.conf", &network_name);
-    let mut phonebook_wg_conf = create_private_file(&path)?;
-    writeln!(phonebook_wg_conf, "{}", phonebook.wg_conf_section(()))?;
-    exec("sudo", ["wg", "addconf", &network_name, &path])?;
+    };
+    let revocations = match revocations {
+        Some(r) => r,
+        None => curl_revocations_list(ctx, network, timeout)?,
+    };
+    evict(ctx, &network_name, &revocations)?;
+    let phonebook = without_revoked(ctx, phonebook, &revocations);
+    add_phonebook_peers(ctx, network, &network_name, &phonebook, mesh, timeout)?;
+    if server {
+        policy::apply(ctx, network, &network_name, &phonebook)?;
+    }
+    if let Some(path) = dynamic_phonebook {
+        write_dynamic_phonebook_file(ctx, path, &network_name, &phonebook)?;
+    }
+    Ok(())
+}
+
+/// How to sync a running Tulip `Network`
+/// - timeout: timeout for querying the phonebook (seconds)
+/// - exit_node: name of the PublicEndpoint all traffic is routed through
+/// - diff_only: only show the changes
+#[derive(Debug, Default)]
+pub struct SyncInput {
+    pub timeout: u64,
+    pub exit_node: Option<String>,
+    pub diff_only: bool,
+}
+
+/// Bring the WireGuard interface of a running Tulip `Network` in line with
+/// its config and phonebook, changing only what differs (see sync.rs).
+/// As in refresh, the `phonebook` and `revocations` are Some in server mode,
+/// and curled from the network's PublicEndpoints otherwise, and in server
+/// mode the network's policy is rebuilt for the phonebook.
+pub fn sync(
+    ctx: &Context,
+    network: &Network,
+    priv_id: PrivId,
+    phonebook: Option<Phonebook>,
+    revocations: Option<Revocations>,
+    input: SyncInput,
+) -> Result<(), NetworkError> {
+    let ctx = &prepare(ctx, network)?;
+    let network_name = get_wg_interface_name(&network.name);
+    ctx.report.interface(&network_name);
+    let server = phonebook.is_some();
+    let phonebook = match phonebook {
+        Some(p) => p,
+        None => curl_phonebook_list(ctx, network, input.timeout)?,
+    };
+    let revocations = match revocations {
+        Some(r) => r,
+        None => curl_revocations_list(ctx, network, input.timeout)?,
+    };
+    let phonebook = without_revoked(ctx, phonebook, &revocations);
+    check_routes(network, &phonebook)?;
+    let changes = sync::reconcile(
+        ctx,
+        network,
+        priv_id,
+        &phonebook,
+        input.exit_node,
+        input.diff_only,
+    )?;
+    if server && !input.diff_only {
+        policy::apply(ctx, network, &network_name, &phonebook)?;
+    }
+    if changes.is_empty() {
+        ctx.report.say(&format!("{} is in sync", network_name));
+    }
+    for change in changes.iter() {
+        ctx.report.say(&change.to_string());
+    }
+    ctx.report
+        .changes(changes.iter().map(|change| change.to_string()).collect());
+    Ok(())
+}
+
+/*
+ * Add the phonebook's users to the WireGuard interface (or, in mesh mode,
+ * the ones we can reach directly), and route the LAN subnets they advertise
+ * through it (except our own)
+ */
+fn add_phonebook_peers(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    phonebook: &Phonebook,
+    mesh: bool,
+    timeout: u64,
+) -> Result<(), NetworkError> {
+    check_routes(network, phonebook)?;
+    if mesh {
+        mesh::add_direct_peers(ctx, network, network_name, phonebook, timeout)?;
+    } else {
+        let path = format!("/tmp/{}_phonebook.conf", network_name);
+        write_staged_file(ctx, &path, &format!("{}\n", phonebook.wg_conf_section(())))?;
+        exec_privileged(ctx, ["wg", "addconf", network_name, &path])?;
+    }
+    for user in phonebook.values() {
+        if user.name == network.user.name {
+            continue;
+        }
+        for route in user.routes.iter() {
+            exec_privileged(ctx, ["ip", "route", "replace", route, "dev", network_name])?;
+        }
+    }
     Ok(())
 }
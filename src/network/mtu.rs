@@ -0,0 +1,95 @@
+use super::{Network, PublicEndpoint};
+use crate::{context::Context, misc::exec_output};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, ToSocketAddrs};
+
+/*
+ * The Tulip interface's MTU
+ * Set in tulip_network.json, for the user or the whole network (the user's
+ * wins), as a number or "auto". Auto looks at the route to each
+ * PublicEndpoint and keeps the smallest MTU, minus WireGuard's overhead for
+ * that address family. Without one, it's DEFAULT_MTU.
+ */
+
+pub const DEFAULT_MTU: u32 = 1420;
+const IPV4_OVERHEAD: u32 = 60;
+const IPV6_OVERHEAD: u32 = 80;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Mtu {
+    Fixed(u32),
+    Auto(Auto),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Auto {
+    Auto,
+}
+
+impl Network {
+    pub fn mtu(&self) -> Option<Mtu> {
+        self.user.mtu.or(self.mtu)
+    }
+}
+
+/// The MTU to set on the interface
+pub fn interface_mtu(ctx: &Context, network: &Network) -> u32 {
+    match network.mtu() {
+        Some(Mtu::Fixed(mtu)) => mtu,
+        Some(Mtu::Auto(_)) => match detect(network) {
+            Some(mtu) => mtu,
+            None => {
+                ctx.report
+                    .warning(&format!("couldn't detect the MTU, using {DEFAULT_MTU}"));
+                DEFAULT_MTU
+            }
+        },
+        None => DEFAULT_MTU,
+    }
+}
+
+/// The smallest MTU on the way to any PublicEndpoint, minus WireGuard's
+/// overhead
+pub fn detect(network: &Network) -> Option<u32> {
+    network
+        .public_endpoints
+        .iter()
+        .filter_map(endpoint_mtu)
+        .min()
+}
+
+fn endpoint_mtu(endpoint: &PublicEndpoint) -> Option<u32> {
+    let port = u16::try_from(endpoint.port).ok()?;
+    let addr = (endpoint.public_hostname.as_str(), port)
+        .to_socket_addrs()
+        .ok()?
+        .next()?
+        .ip();
+    let overhead = match addr {
+        IpAddr::V4(_) => IPV4_OVERHEAD,
+        IpAddr::V6(_) => IPV6_OVERHEAD,
+    };
+    path_mtu(&addr)?.checked_sub(overhead)
+}
+
+/*
+ * The MTU of the route to `addr`: the route's own if it has one, otherwise
+ * its device's
+ */
+fn path_mtu(addr: &IpAddr) -> Option<u32> {
+    let route = exec_output("ip", ["route", "get", &addr.to_string()]).ok()?;
+    let field = |line: &str, name: &str| -> Option<String> {
+        let mut words = line.split_whitespace();
+        words.find(|word| *word == name)?;
+        words.next().map(String::from)
+    };
+    if let Some(mtu) = field(&route, "mtu").and_then(|mtu| mtu.parse().ok()) {
+        return Some(mtu);
+    }
+    let dev = field(&route, "dev")?;
+    let link = exec_output("ip", ["-o", "link", "show", "dev", &dev]).ok()?;
+    field(&link, "mtu")?.parse().ok()
+}
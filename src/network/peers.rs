@@ -0,0 +1,171 @@
+use super::NetworkError;
+use crate::{
+    context::Context,
+    misc::{exec_privileged, exec_privileged_output, exec_privileged_silent},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::UdpSocket,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/*
+ * Helpers for reading and changing the peers of a running WireGuard interface
+ */
+
+/// Public key -> UNIX time of the latest handshake (0 if none yet)
+pub fn latest_handshakes(
+    ctx: &Context,
+    network_name: &str,
+) -> Result<HashMap<String, u64>, NetworkError> {
+    let output = exec_privileged_output(ctx, ["wg", "show", network_name, "latest-handshakes"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let (key, time) = line.split_once('\t')?;
+            Some((key.to_string(), time.trim().parse().unwrap_or_default()))
+        })
+        .collect())
+}
+
+/// Public key -> AllowedIPs
+pub fn allowed_ips(
+    ctx: &Context,
+    network_name: &str,
+) -> Result<HashMap<String, Vec<String>>, NetworkError> {
+    let output = exec_privileged_output(ctx, ["wg", "show", network_name, "allowed-ips"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let (key, ips) = line.split_once('\t')?;
+            let ips = ips
+                .split_whitespace()
+                .filter(|ip| *ip != "(none)")
+                .map(String::from)
+                .collect();
+            Some((key.to_string(), ips))
+        })
+        .collect())
+}
+
+/// Public key -> the ip:port we last received a packet from (if any)
+pub fn endpoints(
+    ctx: &Context,
+    network_name: &str,
+) -> Result<HashMap<String, String>, NetworkError> {
+    let output = exec_privileged_output(ctx, ["wg", "show", network_name, "endpoints"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let (key, endpoint) = line.split_once('\t')?;
+            let endpoint = endpoint.trim();
+            (endpoint != "(none)").then(|| (key.to_string(), endpoint.to_string()))
+        })
+        .collect())
+}
+
+pub fn set_allowed_ips(
+    ctx: &Context,
+    network_name: &str,
+    public_key: &str,
+    allowed_ips: &[String],
+) -> Result<(), NetworkError> {
+    exec_privileged(
+        ctx,
+        [
+            "wg",
+            "set",
+            network_name,
+            "peer",
+            public_key,
+            "allowed-ips",
+            &allowed_ips.join(","),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn remove_peer(
+    ctx: &Context,
+    network_name: &str,
+    public_key: &str,
+) -> Result<(), NetworkError> {
+    exec_privileged(
+        ctx,
+        ["wg", "set", network_name, "peer", public_key, "remove"],
+    )?;
+    Ok(())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// WireGuard only handshakes when it has something to send, so send a
+/// throwaway datagram (to the discard port) through the tunnel.
+/// In a network namespace, the tunnel is only reachable from inside it.
+pub fn probe(ctx: &Context, vpn_ip: &str) {
+    if ctx.dry_run {
+        return;
+    }
+    if ctx.netns.is_some() {
+        let _ = exec_privileged_silent(ctx, ["ping", "-c", "1", "-W", "1", vpn_ip]);
+        return;
+    }
+    if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
+        let _ = socket.send_to(&[0], (vpn_ip, 9));
+    }
+}
+
+/// Hubs and direct peers get a keepalive (seconds) (see wg_conf.rs and
+/// mesh.rs), so that there's always something to send through their session
+pub const KEEPALIVE: u64 = 25;
+
+/*
+ * WireGuard re-handshakes on the first send after the session it initiated
+ * is this old (seconds)
+ */
+const REKEY_AFTER_TIME: u64 = 120;
+
+/// A handshake older than this (seconds) means the peer is gone: with a
+/// keepalive, a live peer re-handshakes at most REKEY_AFTER_TIME + KEEPALIVE
+/// seconds after the last time (plus the handshake itself)
+pub const HANDSHAKE_TIMEOUT: u64 = REKEY_AFTER_TIME + KEEPALIVE + 5;
+
+pub fn is_fresh(handshake: u64) -> bool {
+    handshake > 0 && now().saturating_sub(handshake) < HANDSHAKE_TIMEOUT
+}
+
+/// Probe each (public key, VPN IP) in `peers` and wait up to `timeout` seconds
+/// for all of them to handshake. Returns the public keys that did (in a dry
+/// run, all of them).
+pub fn wait_for_handshakes(
+    ctx: &Context,
+    network_name: &str,
+    peers: &[(&str, &str)],
+    timeout: u64,
+) -> Result<HashSet<String>, NetworkError> {
+    if ctx.dry_run {
+        return Ok(peers.iter().map(|(key, _)| key.to_string()).collect());
+    }
+    for (_, vpn_ip) in peers.iter() {
+        probe(ctx, vpn_ip);
+    }
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        let handshakes = latest_handshakes(ctx, network_name)?;
+        let up: HashSet<String> = peers
+            .iter()
+            .filter(|(key, _)| is_fresh(handshakes.get(*key).copied().unwrap_or(0)))
+            .map(|(key, _)| key.to_string())
+            .collect();
+        if up.len() == peers.len() || Instant::now() >= deadline {
+            return Ok(up);
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
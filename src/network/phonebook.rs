@@ -1,34 +1,249 @@
-use super::{NetworkError, PrivateEndpoint, PublicEndpoint};
-use std::{collections::HashMap, fs, time::Duration};
+use super::{
+    cache::{read_cache, write_cache, PhonebookCache},
+    format,
+    peers::{self, now},
+    source::{first_success, EndpointErrors, Fetched, PhonebookSource, Validators},
+    Network, NetworkError, PrivateEndpoint, PublicEndpoint,
+};
+use crate::context::Context;
+use ipnet::IpNet;
+use std::{collections::BTreeMap, path::Path};
 
-pub type Phonebook = HashMap<String, PrivateEndpoint>;
+pub type Phonebook = BTreeMap<String, PrivateEndpoint>;
 
+/// Curl the phonebook from all PublicEndpoints at once, each from its
+/// configured source (see source.rs), and keep the first valid one.
+/// The last good phonebook is cached (see cache.rs): it's only downloaded
+/// again if it changed, and used as is when no PublicEndpoint answers.
 pub fn curl_phonebook_list(
-    list: &Vec<PublicEndpoint>,
+    ctx: &Context,
+    network: &Network,
     timeout: u64,
 ) -> Result<Phonebook, NetworkError> {
-    for endpoint in list.iter() {
-        let res = curl_phonebook(&endpoint.vpn_ip, timeout);
-        if res.is_ok() {
-            return res;
+    let endpoints: Vec<&PublicEndpoint> = network.public_endpoints.iter().collect();
+    curl_phonebook_from(ctx, network, &endpoints, vec![], timeout)
+}
+
+/// Like curl_phonebook_list, but only from `endpoints`. `skipped` says why the
+/// other PublicEndpoints weren't asked, and is reported with the curl errors.
+pub fn curl_phonebook_from(
+    ctx: &Context,
+    network: &Network,
+    endpoints: &[&PublicEndpoint],
+    skipped: EndpointErrors,
+    timeout: u64,
+) -> Result<Phonebook, NetworkError> {
+    let cache = read_cache(&network.name);
+    let attempts = endpoints
+        .iter()
+        .map(|endpoint| {
+            let source = network.phonebook_source(endpoint);
+            let url = source.url(&endpoint.vpn_ip);
+            let validators = cache
+                .as_ref()
+                .map(|cache| cache.validators_for(&url))
+                .unwrap_or_default();
+            let attempt = move || curl_phonebook(&source, url, validators, timeout);
+            (endpoint.name.clone(), attempt)
+        })
+        .collect();
+    match (first_success(attempts), cache) {
+        (Ok((url, Some(phonebook), validators)), _) => Ok(cache_phonebook(
+            ctx,
+            &network.name,
+            PhonebookCache {
+                url,
+                validators,
+                fetched: now(),
+                phonebook,
+            },
+        )),
+        (Ok((url, None, validators)), Some(cache)) => Ok(cache_phonebook(
+            ctx,
+            &network.name,
+            PhonebookCache {
+                url,
+                validators,
+                fetched: now(),
+                ..cache
+            },
+        )),
+        (Ok((url, None, _)), None) => Err(NetworkError::CurlsFailed(format!(
+            "{url} answered 304 Not Modified but nothing is cached"
+        ))),
+        (Err(errors), Some(cache)) => {
+            for (name, e) in skipped.iter().chain(errors.iter()) {
+                ctx.report
+                    .warning(&format!("couldn't curl the phonebook from {name}: {e}"));
+            }
+            ctx.report
+                .warning(&format!("using the phonebook cached {}s ago", cache.age()));
+            Ok(cache.phonebook)
+        }
+        (Err(errors), None) => Err(NetworkError::CurlsFailed(format!(
+            "couldn't curl the phonebook from any public endpoint: {}",
+            skipped
+                .iter()
+                .chain(errors.iter())
+                .map(|(name, e)| format!("{name}: {e}"))
+                .collect::<Vec<String>>()
+                .join("; ")
+        ))),
+    }
+}
+
+/*
+ * Curl one PublicEndpoint's phonebook, unless it didn't change since
+ * `validators` (then None)
+ */
+fn curl_phonebook(
+    source: &PhonebookSource,
+    url: String,
+    validators: Validators,
+    timeout: u64,
+) -> Result<(String, Option<Phonebook>, Validators), NetworkError> {
+    match source.fetch_if_changed(&url, timeout, &validators)? {
+        Fetched::NotModified => Ok((url, None, validators)),
+        Fetched::Changed { body, validators } => {
+            let phonebook: Phonebook = serde_json::from_str(&body)?;
+            Ok((url, Some(phonebook), validators))
         }
     }
-    Err(NetworkError::CurlsFailed(String::from(
-        "all curl requests to /phonebook.json failed",
-    )))
 }
 
-fn curl_phonebook(vpn_ip: &str, timeout: u64) -> Result<Phonebook, NetworkError> {
-    let url = format!("http://{}/phonebook.json", vpn_ip);
-    let phonebook: Phonebook = ureq::get(&url)
-        .timeout(Duration::from_secs(timeout))
-        .call()?
-        .into_json()?;
-    Ok(phonebook)
+fn cache_phonebook(ctx: &Context, network_name: &str, cache: PhonebookCache) -> Phonebook {
+    if let Err(e) = write_cache(ctx, network_name, &cache) {
+        ctx.report
+            .warning(&format!("couldn't cache the phonebook: {e}"));
+    }
+    cache.phonebook
+}
+
+pub fn read_phonebook_file(ctx: &Context, path: String) -> Result<Phonebook, NetworkError> {
+    format::read_file(ctx, path)
+}
+
+pub fn write_phonebook_file<P: AsRef<Path>>(
+    ctx: &Context,
+    path: P,
+    phonebook: &Phonebook,
+) -> Result<(), NetworkError> {
+    format::write_file(ctx, path, phonebook)
+}
+
+/// Write the phonebook as the hub sees it: each user's entry gets the
+/// `observed_endpoint` WireGuard last heard from them at, and the time of
+/// their `last_handshake`, for clients to connect directly and show presence.
+/// Users the hub never heard from are written as they are.
+/// It's served to clients, so it's always JSON.
+pub fn write_dynamic_phonebook_file(
+    ctx: &Context,
+    path: &str,
+    network_name: &str,
+    phonebook: &Phonebook,
+) -> Result<(), NetworkError> {
+    if ctx.dry_run {
+        ctx.report
+            .say(&format!("# would write the dynamic phonebook to {}", path));
+        return Ok(());
+    }
+    let endpoints = peers::endpoints(ctx, network_name)?;
+    let handshakes = peers::latest_handshakes(ctx, network_name)?;
+    let dynamic: Phonebook = phonebook
+        .iter()
+        .map(|(name, user)| {
+            let mut user = user.clone();
+            user.observed_endpoint = endpoints.get(&user.public_key).cloned();
+            user.last_handshake = handshakes.get(&user.public_key).copied().filter(|&t| t > 0);
+            (name.clone(), user)
+        })
+        .collect();
+    format::write_file_as(format::Format::Json, path, &dynamic)
+}
+
+/// Make sure the LAN routes advertised in the phonebook are valid prefixes,
+/// and that none of them overlaps another one or the network's subnet.
+pub fn check_routes(network: &Network, phonebook: &Phonebook) -> Result<(), NetworkError> {
+    let subnet: IpNet = network
+        .subnet
+        .parse()
+        .map_err(|_| NetworkError::BadSubnet(format!("{} is not a subnet", &network.subnet)))?;
+    let mut seen: Vec<(IpNet, &str)> = vec![(subnet, "the network's subnet")];
+    for user in phonebook.values() {
+        for route in user.routes.iter() {
+            let prefix: IpNet = route.parse().map_err(|_| {
+                NetworkError::BadSubnet(format!("{}'s route {} is not a subnet", &user.name, route))
+            })?;
+            if prefix != prefix.trunc() {
+                return Err(NetworkError::BadSubnet(format!(
+                    "{}'s route {} has host bits set (did you mean {}?)",
+                    &user.name,
+                    route,
+                    prefix.trunc()
+                )));
+            }
+            if let Some((other, owner)) = seen
+                .iter()
+                .find(|(other, _)| other.contains(&prefix) || prefix.contains(other))
+            {
+                return Err(NetworkError::BadSubnet(format!(
+                    "{}'s route {} overlaps {} ({})",
+                    &user.name, route, other, owner
+                )));
+            }
+            seen.push((prefix, &user.name));
+        }
+    }
+    Ok(())
 }
 
-pub fn read_phonebook_file(path: String) -> Result<Phonebook, NetworkError> {
-    let priv_id_json = fs::read_to_string(path)?;
-    let res = serde_json::from_str(&priv_id_json)?;
-    Ok(res)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{network, phonebook_of, user};
+    use serde_json::json;
+
+    fn check(routes: &[(&str, &[&str])]) -> Result<(), NetworkError> {
+        let phonebook = phonebook_of(
+            routes
+                .iter()
+                .enumerate()
+                .map(|(i, (name, routes))| {
+                    user(
+                        name,
+                        &format!("10.0.0.{}", i + 10),
+                        json!({ "routes": routes }),
+                    )
+                })
+                .collect(),
+        );
+        check_routes(&network(json!({})), &phonebook)
+    }
+
+    #[test]
+    fn disjoint_routes_pass() {
+        assert!(check(&[
+            ("bob", &["192.168.5.0/24", "fd00:5::/64"]),
+            ("carl", &["192.168.6.0/24"]),
+            ("dora", &[]),
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn bad_routes_fail() {
+        for routes in [
+            &[("bob", &["192.168.5.0"][..])][..],
+            &[("bob", &["192.168.5.1/24"])],
+            &[("bob", &["10.0.8.0/24"])],
+            &[("bob", &["10.0.0.0/8"])],
+            &[("bob", &["192.168.0.0/16"]), ("carl", &["192.168.6.0/24"])],
+            &[("bob", &["192.168.5.0/24", "192.168.5.0/24"])],
+        ] {
+            assert!(
+                matches!(check(routes), Err(NetworkError::BadSubnet(_))),
+                "{routes:?}"
+            );
+        }
+    }
 }
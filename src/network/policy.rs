@@ -0,0 +1,330 @@
+use super::{phonebook::Phonebook, Network, NetworkError, PrivateEndpoint};
+use crate::{
+    context::Context,
+    misc::{exec_privileged, exec_privileged_silent, write_staged_file},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/*
+ * Access control between users, enforced on the hub
+ * Users carry `groups` in the phonebook, and the network's `policy` says
+ * which group may reach which (optionally only on a protocol and ports).
+ * "any" stands for every user in `from`, and for any destination at all
+ * (including the Internet, for exit nodes) in `to`. A group covers its
+ * members' VPN IPs and the LAN `routes` they're gateways for.
+ * In --server mode, the rules become the forward chain of an nftables table
+ * of their own on the Tulip interface: replies and the allowed traffic go
+ * through, anything else coming in from the tunnel is dropped. The table is
+ * rebuilt whenever the hub re-reads the phonebook, and goes away on stop.
+ * Without a policy, there is no table and everyone can reach everyone.
+ * Traffic to the hub itself (e.g. the phonebook) isn't filtered.
+ */
+
+pub const ANY: &str = "any";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct PolicyRule {
+    /// Group the traffic comes from, or "any"
+    pub from: String,
+    /// Group the traffic goes to, or "any"
+    pub to: String,
+    /// Only this protocol
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+    /// Only these ports (with a tcp or udp protocol)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Protocol {
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+        }
+    }
+}
+
+fn ports_error(rule: &PolicyRule) -> NetworkError {
+    NetworkError::BadPolicy(format!(
+        "{} -> {}: ports need a tcp or udp protocol",
+        rule.from, rule.to
+    ))
+}
+
+/// Make sure the policy compiles before the hub starts
+pub fn check(network: &Network) -> Result<(), NetworkError> {
+    for rule in network.policy.iter() {
+        if !rule.ports.is_empty()
+            && ![Some(Protocol::Tcp), Some(Protocol::Udp)].contains(&rule.protocol)
+        {
+            return Err(ports_error(rule));
+        }
+    }
+    Ok(())
+}
+
+fn policy_table(network_name: &str) -> String {
+    format!("{}_policy", network_name)
+}
+
+/*
+ * The addresses of a group's members: their VPN IPs and LAN routes, IPv6 or
+ * IPv4 ones (nftables wants them in separate sets)
+ */
+fn group_addresses(phonebook: &Phonebook, group: &str, ipv6: bool) -> Vec<String> {
+    let mut users: Vec<&PrivateEndpoint> = phonebook
+        .values()
+        .filter(|user| group == ANY || user.groups.iter().any(|g| g == group))
+        .collect();
+    users.sort_by(|a, b| a.vpn_ip.cmp(&b.vpn_ip));
+    users
+        .into_iter()
+        .flat_map(|user| {
+            [user.vpn_ip.clone()]
+                .into_iter()
+                .chain(user.routes.iter().cloned())
+        })
+        .filter(|address| address.contains(':') == ipv6)
+        .collect()
+}
+
+/*
+ * The protocol and ports part of a rule, for IPv6 or IPv4 traffic (or both,
+ * with None)
+ */
+fn protocol_match(rule: &PolicyRule, ipv6: Option<bool>) -> Result<Option<String>, NetworkError> {
+    match (rule.protocol, rule.ports.is_empty()) {
+        (None, true) => Ok(None),
+        (Some(Protocol::Icmp), true) => Ok(Some(String::from(match ipv6 {
+            None => "meta l4proto { icmp, ipv6-icmp }",
+            Some(false) => "meta l4proto icmp",
+            Some(true) => "meta l4proto ipv6-icmp",
+        }))),
+        (Some(protocol), true) => Ok(Some(format!("meta l4proto {}", protocol.name()))),
+        (Some(protocol @ (Protocol::Tcp | Protocol::Udp)), false) => {
+            let ports: Vec<String> = rule.ports.iter().map(u16::to_string).collect();
+            Ok(Some(format!(
+                "{} dport {{ {} }}",
+                protocol.name(),
+                ports.join(", ")
+            )))
+        }
+        _ => Err(ports_error(rule)),
+    }
+}
+
+fn accept(rule: &PolicyRule, matches: Vec<String>) -> String {
+    format!(
+        "{} accept comment \"{} -> {}\"",
+        matches.join(" "),
+        rule.from,
+        rule.to
+    )
+}
+
+/*
+ * nft_rules(rule, phonebook, network_name)
+ * -------------------------------
+ * The nftables rules for a PolicyRule: one per address family its groups
+ * have members in (or a single one between "any" and "any"), none if one of
+ * its groups has no members
+ */
+fn nft_rules(
+    rule: &PolicyRule,
+    phonebook: &Phonebook,
+    network_name: &str,
+) -> Result<Vec<String>, NetworkError> {
+    let iifname = format!("iifname \"{}\"", network_name);
+    if rule.from == ANY && rule.to == ANY {
+        let matches = [Some(iifname), protocol_match(rule, None)?];
+        return Ok(vec![accept(rule, matches.into_iter().flatten().collect())]);
+    }
+    let mut rules = vec![];
+    'families: for (family, ipv6) in [("ip", false), ("ip6", true)] {
+        let mut matches = vec![iifname.clone()];
+        for (group, direction) in [(&rule.from, "saddr"), (&rule.to, "daddr")] {
+            if group == ANY {
+                continue;
+            }
+            let addresses = group_addresses(phonebook, group, ipv6);
+            if addresses.is_empty() {
+                continue 'families;
+            }
+            matches.push(format!(
+                "{} {} {{ {} }}",
+                family,
+                direction,
+                addresses.join(", ")
+            ));
+        }
+        matches.extend(protocol_match(rule, Some(ipv6))?);
+        rules.push(accept(rule, matches));
+    }
+    Ok(rules)
+}
+
+/// The nftables table enforcing the network's policy, replacing any previous
+/// one
+pub fn ruleset(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    phonebook: &Phonebook,
+) -> Result<String, NetworkError> {
+    let mut rules = vec![];
+    for policy_rule in network.policy.iter() {
+        let policy_rules = nft_rules(policy_rule, phonebook, network_name)?;
+        if policy_rules.is_empty() {
+            ctx.report.say(&format!(
+                "skipping policy {} -> {}: no users in the group",
+                policy_rule.from, policy_rule.to
+            ));
+        }
+        rules.extend(policy_rules);
+    }
+    let rules: String = rules
+        .iter()
+        .map(|rule| format!("        {}\n", rule))
+        .collect();
+    Ok(format!(
+        "add table inet {table}
+flush table inet {table}
+table inet {table} {{
+    chain forward {{
+        type filter hook forward priority 0; policy accept;
+        iifname != \"{network_name}\" accept
+        ct state established,related accept
+{rules}        drop
+    }}
+}}
+",
+        table = policy_table(network_name),
+    ))
+}
+
+/// Enforce the network's policy on the hub, for the users in `phonebook`
+pub fn apply(
+    ctx: &Context,
+    network: &Network,
+    network_name: &str,
+    phonebook: &Phonebook,
+) -> Result<(), NetworkError> {
+    if network.policy.is_empty() {
+        return Ok(());
+    }
+    let path = format!("/tmp/{}_policy.nft", network_name);
+    write_staged_file(ctx, &path, &ruleset(ctx, network, network_name, phonebook)?)?;
+    exec_privileged(ctx, ["nft", "-f", &path])?;
+    Ok(())
+}
+
+/// Errors are ignored, since the network may not have had a policy
+pub fn remove(ctx: &Context, network_name: &str) -> Result<(), NetworkError> {
+    let _ = exec_privileged_silent(
+        ctx,
+        [
+            "nft",
+            "delete",
+            "table",
+            "inet",
+            &policy_table(network_name),
+        ],
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{network, phonebook};
+    use serde_json::{json, Value};
+
+    /*
+     * The accept rules of the ruleset for `policy`
+     */
+    fn rules(policy: Value) -> Result<Vec<String>, NetworkError> {
+        let ruleset = ruleset(
+            &Context::default(),
+            &network(json!({ "policy": policy })),
+            "tulip_sandring",
+            &phonebook(),
+        )?;
+        Ok(ruleset
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("iifname \"tulip_sandring\" "))
+            .map(str::to_string)
+            .collect())
+    }
+
+    #[test]
+    fn ruleset_splits_address_families() {
+        assert_eq!(
+            rules(json!([{ "from": "ops", "to": "web" }])).unwrap(),
+            ["iifname \"tulip_sandring\" ip saddr { 10.0.0.4 } \
+                 ip daddr { 10.0.0.5, 192.168.5.0/24 } accept comment \"ops -> web\"",]
+        );
+        assert_eq!(
+            rules(json!([{ "from": "any", "to": "web" }])).unwrap(),
+            [
+                "iifname \"tulip_sandring\" ip daddr { 10.0.0.5, 192.168.5.0/24 } \
+                 accept comment \"any -> web\"",
+                "iifname \"tulip_sandring\" ip6 daddr { fd00:5::/64 } \
+                 accept comment \"any -> web\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn ruleset_matches_protocols_and_ports() {
+        assert_eq!(
+            rules(json!([
+                { "from": "ops", "to": "web", "protocol": "tcp", "ports": [22, 443] },
+                { "from": "any", "to": "any", "protocol": "icmp" },
+                { "from": "any", "to": "web", "protocol": "icmp" }
+            ]))
+            .unwrap(),
+            [
+                "iifname \"tulip_sandring\" ip saddr { 10.0.0.4 } \
+                 ip daddr { 10.0.0.5, 192.168.5.0/24 } tcp dport { 22, 443 } \
+                 accept comment \"ops -> web\"",
+                "iifname \"tulip_sandring\" meta l4proto { icmp, ipv6-icmp } \
+                 accept comment \"any -> any\"",
+                "iifname \"tulip_sandring\" ip daddr { 10.0.0.5, 192.168.5.0/24 } \
+                 meta l4proto icmp accept comment \"any -> web\"",
+                "iifname \"tulip_sandring\" ip6 daddr { fd00:5::/64 } \
+                 meta l4proto ipv6-icmp accept comment \"any -> web\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn ruleset_skips_empty_groups() {
+        let ruleset = rules(json!([
+            { "from": "ops", "to": "nobody" },
+            { "from": "nobody", "to": "any" }
+        ]));
+        assert!(ruleset.unwrap().is_empty());
+    }
+
+    #[test]
+    fn ruleset_rejects_ports_without_tcp_or_udp() {
+        for protocol in [json!(null), json!("icmp")] {
+            let ruleset = rules(json!([
+                { "from": "ops", "to": "web", "protocol": protocol, "ports": [53] }
+            ]));
+            assert!(matches!(ruleset, Err(NetworkError::BadPolicy(_))));
+        }
+    }
+}
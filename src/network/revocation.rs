@@ -0,0 +1,147 @@
+use super::{
+    cache::{read_revocations_cache, write_revocations_cache},
+    phonebook::Phonebook,
+    source::{first_success, is_not_found, sibling_url},
+    Network, NetworkError, PublicEndpoint,
+};
+use crate::{
+    context::Context,
+    misc::{create_private_file, exec_privileged, exec_privileged_output},
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::Path};
+
+/// The revocations.json format
+///
+/// Keys deliberately removed from a network, served next to phonebook.json.
+/// Revoked keys are evicted from running interfaces and never re-added.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Revocation {
+    pub name: String,
+    pub public_key: String,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+pub type Revocations = Vec<Revocation>;
+
+pub fn is_revoked(revocations: &[Revocation], public_key: &str) -> bool {
+    revocations
+        .iter()
+        .any(|revocation| revocation.public_key == public_key)
+}
+
+/// The phonebook minus its revoked users
+pub fn without_revoked(
+    ctx: &Context,
+    phonebook: Phonebook,
+    revocations: &[Revocation],
+) -> Phonebook {
+    phonebook
+        .into_iter()
+        .filter(|(_, user)| {
+            let revoked = is_revoked(revocations, &user.public_key);
+            if revoked {
+                ctx.report
+                    .warning(&format!("skipping {}: their key was revoked", &user.name));
+            }
+            !revoked
+        })
+        .collect()
+}
+
+/// Remove revoked peers from a running WireGuard interface
+pub fn evict(
+    ctx: &Context,
+    network_name: &str,
+    revocations: &[Revocation],
+) -> Result<(), NetworkError> {
+    let peers = exec_privileged_output(ctx, ["wg", "show", network_name, "peers"])?;
+    for peer in peers.lines() {
+        if let Some(revocation) = revocations.iter().find(|r| r.public_key == peer) {
+            ctx.report.say(&format!(
+                "evicting {} ({}): {}",
+                &revocation.name, &revocation.public_key, &revocation.reason
+            ));
+            exec_privileged(ctx, ["wg", "set", network_name, "peer", peer, "remove"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Curl revocations.json (next to phonebook.json) from all PublicEndpoints at
+/// once, and keep the first answer. A hub without one (404) doesn't count as
+/// an answer: the network has no revocations only if no hub has any.
+/// When no hub answers, use the last revocations we curled (see cache.rs),
+/// and fail if there are none, rather than let revoked keys back in.
+pub fn curl_revocations_list(
+    ctx: &Context,
+    network: &Network,
+    timeout: u64,
+) -> Result<Revocations, NetworkError> {
+    let endpoints: Vec<&PublicEndpoint> = network.public_endpoints.iter().collect();
+    curl_revocations_from(ctx, network, &endpoints, timeout)
+}
+
+/// Like curl_revocations_list, but only from `endpoints`
+pub fn curl_revocations_from(
+    ctx: &Context,
+    network: &Network,
+    endpoints: &[&PublicEndpoint],
+    timeout: u64,
+) -> Result<Revocations, NetworkError> {
+    let attempts = endpoints
+        .iter()
+        .map(|endpoint| {
+            let source = network.phonebook_source(endpoint);
+            let url = sibling_url(&source.url(&endpoint.vpn_ip), "revocations.json");
+            let attempt = move || {
+                let json = source.fetch(&url, timeout)?;
+                Ok(serde_json::from_str::<Revocations>(&json)?)
+            };
+            (endpoint.name.clone(), attempt)
+        })
+        .collect();
+    let errors = match first_success(attempts) {
+        Ok(revocations) => {
+            write_revocations_cache(ctx, &network.name, &revocations)?;
+            return Ok(revocations);
+        }
+        Err(errors) => errors,
+    };
+    if let Some(revocations) = read_revocations_cache(&network.name) {
+        for (name, e) in errors.iter() {
+            ctx.report
+                .warning(&format!("couldn't curl revocations.json from {name}: {e}"));
+        }
+        ctx.report.warning("using the cached revocations");
+        return Ok(revocations);
+    }
+    if !errors.is_empty() && errors.iter().all(|(_, e)| is_not_found(e)) {
+        return Ok(Revocations::new());
+    }
+    Err(NetworkError::CurlsFailed(format!(
+        "couldn't curl revocations.json from any public endpoint: {}",
+        errors
+            .iter()
+            .map(|(name, e)| format!("{name}: {e}"))
+            .collect::<Vec<String>>()
+            .join("; ")
+    )))
+}
+
+pub fn read_revocations_file(path: &str) -> Result<Revocations, NetworkError> {
+    if !Path::new(path).exists() {
+        return Ok(Revocations::new());
+    }
+    let revocations_json = fs::read_to_string(path)?;
+    let res = serde_json::from_str(&revocations_json)?;
+    Ok(res)
+}
+
+pub fn write_revocations_file(path: &str, revocations: &Revocations) -> Result<(), NetworkError> {
+    let revocations_json = serde_json::to_string_pretty(revocations)?;
+    let mut file = create_private_file(path)?;
+    writeln!(file, "{}", revocations_json)?;
+    Ok(())
+}
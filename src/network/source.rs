@@ -0,0 +1,259 @@
+use super::NetworkError;
+use crate::schema;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ServerName,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Where to fetch a network's phonebook.json (and revocations.json) from
+///
+/// Set per PublicEndpoint, or for the whole network. Without one, it's
+/// http://<vpn_ip>/phonebook.json on each PublicEndpoint.
+/// - url: a full http://, https:// or file:// URL, overriding everything else
+/// - scheme: http (default) or https
+/// - port: defaults to the scheme's
+/// - path: defaults to /phonebook.json
+/// - pin_sha256: with https, the hex SHA-256 of the hub's (DER) certificate.
+///   The certificate is then trusted on that alone, so it may be self-signed.
+///
+/// revocations.json is expected next to phonebook.json.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct PhonebookSource {
+    /// Full http://, https:// or file:// URL, overriding the other fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "schema::uri")]
+    pub url: Option<String>,
+    /// http (default) or https
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "schema::scheme")]
+    pub scheme: Option<String>,
+    /// Port, if not the scheme's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Path, if not /phonebook.json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// With https, the hex SHA-256 of the hub's (DER) certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "schema::sha256")]
+    pub pin_sha256: Option<String>,
+}
+
+impl PhonebookSource {
+    /// The phonebook's URL on the PublicEndpoint at `vpn_ip`
+    pub fn url(&self, vpn_ip: &str) -> String {
+        if let Some(url) = &self.url {
+            return url.clone();
+        }
+        let scheme = self.scheme.as_deref().unwrap_or("http");
+        let port = self.port.map(|p| format!(":{p}")).unwrap_or_default();
+        let path = self.path.as_deref().unwrap_or("/phonebook.json");
+        let slash = if path.starts_with('/') { "" } else { "/" };
+        format!("{scheme}://{vpn_ip}{port}{slash}{path}")
+    }
+
+    /// Read the file at `url` (http://, https:// or file://)
+    pub fn fetch(&self, url: &str, timeout: u64) -> Result<String, NetworkError> {
+        match self.fetch_if_changed(url, timeout, &Validators::default())? {
+            Fetched::Changed { body, .. } => Ok(body),
+            Fetched::NotModified => Err(NetworkError::CurlsFailed(format!(
+                "{url} answered 304 Not Modified to an unconditional request"
+            ))),
+        }
+    }
+
+    /// Like fetch, but only download the file if it changed since the copy
+    /// `validators` came with (If-None-Match/If-Modified-Since).
+    /// file:// URLs are always read.
+    pub fn fetch_if_changed(
+        &self,
+        url: &str,
+        timeout: u64,
+        validators: &Validators,
+    ) -> Result<Fetched, NetworkError> {
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Fetched::Changed {
+                body: fs::read_to_string(path)?,
+                validators: Validators::default(),
+            });
+        }
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(NetworkError::BadSource(format!(
+                "{url} is not an http://, https:// or file:// URL"
+            )));
+        }
+        let mut agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(timeout));
+        if let Some(pin) = &self.pin_sha256 {
+            agent = agent.tls_config(Arc::new(pinned_tls_config(pin)?));
+        }
+        let mut request = agent.build().get(url);
+        if let Some(etag) = &validators.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+        let response = request.call()?;
+        if response.status() == 304 {
+            return Ok(Fetched::NotModified);
+        }
+        let validators = Validators {
+            etag: response.header("ETag").map(String::from),
+            last_modified: response.header("Last-Modified").map(String::from),
+        };
+        Ok(Fetched::Changed {
+            body: response.into_string()?,
+            validators,
+        })
+    }
+}
+
+/// What a server told us to identify the version of a file we downloaded
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+pub enum Fetched {
+    NotModified,
+    Changed {
+        body: String,
+        validators: Validators,
+    },
+}
+
+/// Why each PublicEndpoint (by name) couldn't be used
+pub type EndpointErrors = Vec<(String, NetworkError)>;
+
+/// Run every (endpoint name, attempt) at once, each in its own thread, and
+/// return the first one to succeed without waiting for the others.
+/// If none does, return each endpoint's error.
+pub fn first_success<T, F>(attempts: Vec<(String, F)>) -> Result<T, EndpointErrors>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, NetworkError> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    for (name, attempt) in attempts {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let _ = tx.send((name, attempt()));
+        });
+    }
+    drop(tx);
+    let mut errors = vec![];
+    for (name, res) in rx.iter() {
+        match res {
+            Ok(value) => return Ok(value),
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    Err(errors)
+}
+
+/// The URL of `file` in the same directory as `url`
+pub fn sibling_url(url: &str, file: &str) -> String {
+    match url.rsplit_once('/') {
+        Some((dir, _)) => format!("{dir}/{file}"),
+        None => file.to_string(),
+    }
+}
+
+/// Whether a fetch failed because there's nothing at that URL
+pub fn is_not_found(e: &NetworkError) -> bool {
+    match e {
+        NetworkError::Ureq(e) => matches!(**e, ureq::Error::Status(404, _)),
+        NetworkError::FileIO(e) => e.kind() == std::io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+struct PinnedCertificate {
+    sha256: String,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest: String = Sha256::digest(&end_entity.0)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if digest == self.sha256 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "certificate sha256 {digest} doesn't match the pinned one"
+            )))
+        }
+    }
+}
+
+fn pinned_tls_config(pin: &str) -> Result<ClientConfig, NetworkError> {
+    let sha256: String = pin
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(NetworkError::BadSource(format!(
+            "{pin} is not a hex SHA-256"
+        )));
+    }
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate { sha256 }))
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sibling_url_replaces_the_last_segment() {
+        assert_eq!(
+            sibling_url("http://10.0.0.1/phonebook.json", "revocations.json"),
+            "http://10.0.0.1/revocations.json"
+        );
+        assert_eq!(
+            sibling_url("https://hub:8443/tulip/pb.json?v=1", "revocations.json"),
+            "https://hub:8443/tulip/revocations.json"
+        );
+        assert_eq!(
+            sibling_url("file:///etc/tulip/phonebook.json", "revocations.json"),
+            "file:///etc/tulip/revocations.json"
+        );
+        assert_eq!(
+            sibling_url("phonebook.json", "revocations.json"),
+            "revocations.json"
+        );
+    }
+
+    #[test]
+    fn only_missing_files_are_not_found() {
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(is_not_found(&NetworkError::FileIO(missing)));
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(!is_not_found(&NetworkError::FileIO(denied)));
+    }
+}
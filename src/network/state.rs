@@ -0,0 +1,89 @@
+use super::{
+    cache::{read_cache_file, remove_cache_file, write_cache_file},
+    NetworkError,
+};
+use crate::{
+    context::Context,
+    misc::{exec_privileged, exec_privileged_output},
+};
+use serde::{Deserialize, Serialize};
+
+/*
+ * What a running network changed outside its interface
+ * Routing rules and kernel parameters are global, and may be shared with
+ * wg-quick or other Tulip networks, so start records the rules it added and
+ * the parameters it changed (with their previous values) in the network's
+ * store directory (see store.rs), and stop undoes exactly those.
+ */
+
+pub const STATE_FILE: &str = "state.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    #[serde(default)]
+    pub rules: Vec<IpRule>,
+    #[serde(default)]
+    pub sysctls: Vec<Sysctl>,
+}
+
+/// An `ip rule`, e.g. family "-4" and selector ["not", "fwmark", "1", "table", "1"]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpRule {
+    pub family: String,
+    pub selector: Vec<String>,
+}
+
+/// A kernel parameter start changed, and the value it had before
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sysctl {
+    pub key: String,
+    pub previous: String,
+}
+
+/// Set the kernel parameter `key`, recording its previous value in `state` if
+/// that changes it
+pub fn set_sysctl(
+    ctx: &Context,
+    state: &mut State,
+    key: &str,
+    value: &str,
+) -> Result<(), NetworkError> {
+    let previous = exec_privileged_output(ctx, ["sysctl", "-n", key])?;
+    exec_privileged(ctx, ["sysctl", "-q", "-w", &format!("{key}={value}")])?;
+    if previous.trim() != value {
+        state.sysctls.push(Sysctl {
+            key: key.to_string(),
+            previous: previous.trim().to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Put the kernel parameters `state` changed back the way they were
+pub fn restore_sysctls(ctx: &Context, state: &State) -> Result<(), NetworkError> {
+    for sysctl in state.sysctls.iter() {
+        exec_privileged(
+            ctx,
+            [
+                "sysctl",
+                "-q",
+                "-w",
+                &format!("{}={}", sysctl.key, sysctl.previous),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// A missing state is an empty one: start changed nothing (that we know of)
+pub fn read_state(network_name: &str) -> State {
+    read_cache_file(network_name, STATE_FILE).unwrap_or_default()
+}
+
+pub fn write_state(ctx: &Context, network_name: &str, state: &State) -> Result<(), NetworkError> {
+    write_cache_file(ctx, network_name, STATE_FILE, state)
+}
+
+pub fn remove_state(ctx: &Context, network_name: &str) -> Result<(), NetworkError> {
+    remove_cache_file(ctx, network_name, STATE_FILE)
+}
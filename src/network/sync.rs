@@ -0,0 +1,681 @@
+use super::{
+    failover::FULL_TUNNEL,
+    peers::{remove_peer, set_allowed_ips, KEEPALIVE},
+    phonebook::Phonebook,
+    wg_conf::{get_wg_interface_name, NetworkWgConfInput, WgConfSection},
+    Network, NetworkError,
+};
+use crate::{
+    context::Context,
+    id::PrivId,
+    misc::{exec_privileged, exec_privileged_output},
+};
+use ipnet::IpNet;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    net::ToSocketAddrs,
+};
+
+/*
+ * Reconciling a running interface with its config
+ * The desired state is what start would configure: the network's and the
+ * phonebook's wg_conf_sections (parsed back), the user's address, and routes
+ * for the subnet and the users' LANs. The live state comes from
+ * `wg show dump` and `ip`. Only the differences are applied, so the tunnels
+ * that didn't change stay up.
+ * - Endpoints are only compared for peers the config gives one (the hubs),
+ *    since WireGuard learns the others' as they roam.
+ * - Whichever hub carries the subnet after a failover (see failover.rs) keeps
+ *    it.
+ * - Interfaces started with --mesh have peers set up by mesh.rs, which sync
+ *    would undo: use `tulip watch --mesh` for those.
+ */
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerState {
+    pub name: Option<String>,
+    pub allowed_ips: BTreeSet<String>,
+    pub endpoint: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct InterfaceState {
+    pub public_key: Option<String>,
+    pub listen_port: Option<u64>,
+    pub peers: BTreeMap<String, PeerState>,
+    pub addresses: BTreeSet<String>,
+    pub routes: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+pub enum Change {
+    AddPeer(String, PeerState),
+    RemovePeer(String, PeerState),
+    SetAllowedIps(String, PeerState, BTreeSet<String>),
+    SetEndpoint(String, PeerState, String),
+    SetListenPort(Option<u64>, u64),
+    AddAddress(String),
+    RemoveAddress(String),
+    AddRoute(String),
+    RemoveRoute(String),
+}
+
+fn join(ips: &BTreeSet<String>) -> String {
+    ips.iter().cloned().collect::<Vec<String>>().join(", ")
+}
+
+fn peer_label(public_key: &str, peer: &PeerState) -> String {
+    match &peer.name {
+        Some(name) => format!("{} ({})", name, public_key),
+        None => public_key.to_string(),
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::AddPeer(key, peer) => {
+                write!(
+                    f,
+                    "+ peer {} allowed-ips {}",
+                    peer_label(key, peer),
+                    join(&peer.allowed_ips)
+                )?;
+                match &peer.endpoint {
+                    Some(endpoint) => write!(f, " endpoint {}", endpoint),
+                    None => Ok(()),
+                }
+            }
+            Change::RemovePeer(key, peer) => write!(f, "- peer {}", peer_label(key, peer)),
+            Change::SetAllowedIps(key, peer, allowed_ips) => write!(
+                f,
+                "~ peer {} allowed-ips {} -> {}",
+                peer_label(key, peer),
+                join(&peer.allowed_ips),
+                join(allowed_ips)
+            ),
+            Change::SetEndpoint(key, peer, endpoint) => write!(
+                f,
+                "~ peer {} endpoint {} -> {}",
+                peer_label(key, peer),
+                peer.endpoint.as_deref().unwrap_or("(none)"),
+                endpoint
+            ),
+            Change::SetListenPort(from, to) => write!(
+                f,
+                "~ listen-port {} -> {}",
+                from.map(|port| port.to_string())
+                    .unwrap_or(String::from("(none)")),
+                to
+            ),
+            Change::AddAddress(address) => write!(f, "+ address {}", address),
+            Change::RemoveAddress(address) => write!(f, "- address {}", address),
+            Change::AddRoute(route) => write!(f, "+ route {}", route),
+            Change::RemoveRoute(route) => write!(f, "- route {}", route),
+        }
+    }
+}
+
+/*
+ * WireGuard prints prefixes with their host bits cleared
+ */
+fn normalize(ip: &str) -> String {
+    ip.parse::<IpNet>()
+        .map(|net| net.trunc().to_string())
+        .unwrap_or(ip.to_string())
+}
+
+/*
+ * parse_wg_conf(conf)
+ * -------------------------------
+ * The ListenPort and the peers of a config made by wg_conf_section, each
+ * named after the comment above its [Peer]
+ */
+fn parse_wg_conf(conf: &str) -> (Option<u64>, BTreeMap<String, PeerState>) {
+    let mut listen_port = None;
+    let mut peers = BTreeMap::new();
+    let mut name = None;
+    let mut peer: Option<(String, PeerState)> = None;
+    for line in conf.lines().map(str::trim) {
+        if let Some(comment) = line.strip_prefix('#') {
+            name = Some(comment.trim().to_string());
+            continue;
+        }
+        if line.starts_with('[') {
+            if let Some((key, state)) = peer.take() {
+                merge_peer(&mut peers, key, state);
+            }
+            if line == "[Peer]" {
+                peer = Some((
+                    String::new(),
+                    PeerState {
+                        name: name.take(),
+                        ..Default::default()
+                    },
+                ));
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match (key.trim(), &mut peer) {
+            ("ListenPort", None) => listen_port = value.parse().ok(),
+            ("PublicKey", Some((public_key, _))) => {
+                // a base64 key ends with '=', which split_once left alone
+                *public_key = value.to_string();
+            }
+            ("AllowedIPs", Some((_, state))) => {
+                state.allowed_ips = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|ip| !ip.is_empty())
+                    .map(normalize)
+                    .collect();
+            }
+            ("Endpoint", Some((_, state))) => state.endpoint = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if let Some((key, state)) = peer {
+        merge_peer(&mut peers, key, state);
+    }
+    (listen_port, peers)
+}
+
+/*
+ * A hub can be in the phonebook too, and `wg addconf` adds to the
+ * allowed IPs the hub already has
+ */
+fn merge_peer(peers: &mut BTreeMap<String, PeerState>, key: String, state: PeerState) {
+    match peers.get_mut(&key) {
+        Some(existing) => {
+            existing.allowed_ips.extend(state.allowed_ips);
+            existing.endpoint = existing.endpoint.take().or(state.endpoint);
+        }
+        None => {
+            peers.insert(key, state);
+        }
+    }
+}
+
+/// What start configures for `network`, with `phonebook` (without the revoked
+/// users) as its users
+pub fn desired_state(
+    network: &Network,
+    priv_id: PrivId,
+    phonebook: &Phonebook,
+    exit_node: Option<String>,
+) -> InterfaceState {
+    let conf = format!(
+        "{}\n{}",
+        network.wg_conf_section(&NetworkWgConfInput {
+            priv_id,
+            mobile: false,
+            port: network.user.port,
+            exit_node,
+        }),
+        phonebook.wg_conf_section(())
+    );
+    let (listen_port, peers) = parse_wg_conf(&conf);
+    let routes = [network.subnet.clone()]
+        .into_iter()
+        .chain(
+            phonebook
+                .values()
+                .filter(|user| user.name != network.user.name)
+                .flat_map(|user| user.routes.iter().cloned()),
+        )
+        .map(|route| normalize(&route))
+        .collect();
+    InterfaceState {
+        public_key: None,
+        listen_port,
+        peers,
+        addresses: BTreeSet::from([format!("{}/32", network.user.vpn_ip)]),
+        routes,
+    }
+}
+
+/// What the running interface has
+pub fn live_state(ctx: &Context, network_name: &str) -> Result<InterfaceState, NetworkError> {
+    let dump = exec_privileged_output(ctx, ["wg", "show", network_name, "dump"])?;
+    let mut lines = dump.lines();
+    let Some(interface) = lines.next() else {
+        return Err(NetworkError::NoInterface(network_name.to_string()));
+    };
+    let interface: Vec<&str> = interface.split('\t').collect();
+    let mut state = InterfaceState {
+        public_key: interface.get(1).map(|key| key.to_string()),
+        listen_port: interface
+            .get(2)
+            .and_then(|port| port.parse().ok())
+            .filter(|port| *port > 0),
+        ..Default::default()
+    };
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [public_key, _, endpoint, allowed_ips, ..] = fields[..] else {
+            continue;
+        };
+        state.peers.insert(
+            public_key.to_string(),
+            PeerState {
+                name: None,
+                allowed_ips: allowed_ips
+                    .split(',')
+                    .filter(|ip| *ip != "(none)" && !ip.is_empty())
+                    .map(normalize)
+                    .collect(),
+                endpoint: (endpoint != "(none)").then(|| endpoint.to_string()),
+            },
+        );
+    }
+    let addresses = exec_privileged_output(
+        ctx,
+        ["ip", "-o", "-4", "address", "show", "dev", network_name],
+    )?;
+    state.addresses = addresses
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            words.find(|word| *word == "inet")?;
+            words.next().map(String::from)
+        })
+        .collect();
+    for (family, default, host) in [("-4", "0.0.0.0/0", "32"), ("-6", "::/0", "128")] {
+        let routes =
+            exec_privileged_output(ctx, ["ip", family, "route", "show", "dev", network_name])?;
+        state.routes.extend(
+            routes
+                .lines()
+                // the kernel's own, like the link-local fe80::/64
+                .filter(|line| !line.contains("proto kernel"))
+                .filter_map(|line| line.split_whitespace().next())
+                .map(|route| match route {
+                    "default" => String::from(default),
+                    route if !route.contains('/') => format!("{}/{}", route, host),
+                    route => route.to_string(),
+                }),
+        );
+    }
+    Ok(state)
+}
+
+/*
+ * The `ip` option for the family of an address or prefix
+ */
+fn family(prefix: &str) -> &'static str {
+    if prefix.contains(':') {
+        "-6"
+    } else {
+        "-4"
+    }
+}
+
+/*
+ * Whether the live `endpoint` (an ip:port) is where `desired` (a
+ * host:port) resolves to. If it doesn't resolve, leave it be.
+ */
+fn same_endpoint(desired: &str, live: &str) -> bool {
+    match desired.to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|addr| addr.to_string() == live),
+        Err(_) => true,
+    }
+}
+
+/// The changes that turn `live` into `desired`
+pub fn diff(network: &Network, mut desired: InterfaceState, live: &InterfaceState) -> Vec<Change> {
+    /*
+     * WireGuard ignores a peer with the interface's own key (e.g. our own
+     * phonebook entry)
+     */
+    if let Some(own_key) = &live.public_key {
+        desired.peers.remove(own_key);
+    }
+    keep_active_hub(network, &mut desired, live);
+    let mut changes = vec![];
+    if let (Some(port), false) = (desired.listen_port, desired.listen_port == live.listen_port) {
+        changes.push(Change::SetListenPort(live.listen_port, port));
+    }
+    for (key, peer) in desired.peers.iter() {
+        match live.peers.get(key) {
+            None => changes.push(Change::AddPeer(key.clone(), peer.clone())),
+            Some(live_peer) => {
+                let live_peer = PeerState {
+                    name: peer.name.clone(),
+                    ..live_peer.clone()
+                };
+                if live_peer.allowed_ips != peer.allowed_ips {
+                    changes.push(Change::SetAllowedIps(
+                        key.clone(),
+                        live_peer.clone(),
+                        peer.allowed_ips.clone(),
+                    ));
+                }
+                if let Some(endpoint) = &peer.endpoint {
+                    let same = live_peer
+                        .endpoint
+                        .as_deref()
+                        .is_some_and(|live| same_endpoint(endpoint, live));
+                    if !same {
+                        changes.push(Change::SetEndpoint(
+                            key.clone(),
+                            live_peer,
+                            endpoint.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    for (key, peer) in live.peers.iter() {
+        if !desired.peers.contains_key(key) {
+            changes.push(Change::RemovePeer(key.clone(), peer.clone()));
+        }
+    }
+    for address in desired.addresses.difference(&live.addresses) {
+        changes.push(Change::AddAddress(address.clone()));
+    }
+    for address in live.addresses.difference(&desired.addresses) {
+        changes.push(Change::RemoveAddress(address.clone()));
+    }
+    for route in desired.routes.difference(&live.routes) {
+        changes.push(Change::AddRoute(route.clone()));
+    }
+    for route in live.routes.difference(&desired.routes) {
+        if !FULL_TUNNEL.contains(&route.as_str()) {
+            changes.push(Change::RemoveRoute(route.clone()));
+        }
+    }
+    changes
+}
+
+/*
+ * The config gives the subnet to the primary hub, but if another hub has it
+ * (after a failover), it stays there
+ */
+fn keep_active_hub(network: &Network, desired: &mut InterfaceState, live: &InterfaceState) {
+    let subnet = normalize(&network.subnet);
+    let has_subnet = |peers: &BTreeMap<String, PeerState>| -> Option<String> {
+        network
+            .public_endpoints
+            .iter()
+            .map(|hub| hub.public_key.clone())
+            .find(|key| {
+                peers
+                    .get(key)
+                    .is_some_and(|peer| peer.allowed_ips.contains(&subnet))
+            })
+    };
+    let (Some(primary), Some(active)) = (has_subnet(&desired.peers), has_subnet(&live.peers))
+    else {
+        return;
+    };
+    if primary == active || !desired.peers.contains_key(&active) {
+        return;
+    }
+    if let Some(peer) = desired.peers.get_mut(&primary) {
+        peer.allowed_ips.remove(&subnet);
+    }
+    if let Some(peer) = desired.peers.get_mut(&active) {
+        peer.allowed_ips.insert(subnet);
+    }
+}
+
+/// Apply `changes` to the running interface
+pub fn apply(ctx: &Context, network_name: &str, changes: &[Change]) -> Result<(), NetworkError> {
+    for change in changes {
+        match change {
+            Change::AddPeer(key, peer) => {
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(",");
+                let keepalive = KEEPALIVE.to_string();
+                let mut args = vec![
+                    "wg",
+                    "set",
+                    network_name,
+                    "peer",
+                    key,
+                    "allowed-ips",
+                    &allowed_ips,
+                ];
+                // the peers with an endpoint are hubs, which get a keepalive
+                if let Some(endpoint) = &peer.endpoint {
+                    args.extend(["endpoint", endpoint, "persistent-keepalive", &keepalive]);
+                }
+                exec_privileged(ctx, args)?;
+            }
+            Change::RemovePeer(key, _) => remove_peer(ctx, network_name, key)?,
+            Change::SetAllowedIps(key, _, allowed_ips) => {
+                let allowed_ips: Vec<String> = allowed_ips.iter().cloned().collect();
+                set_allowed_ips(ctx, network_name, key, &allowed_ips)?;
+            }
+            Change::SetEndpoint(key, _, endpoint) => {
+                exec_privileged(
+                    ctx,
+                    ["wg", "set", network_name, "peer", key, "endpoint", endpoint],
+                )?;
+            }
+            Change::SetListenPort(_, port) => {
+                exec_privileged(
+                    ctx,
+                    ["wg", "set", network_name, "listen-port", &port.to_string()],
+                )?;
+            }
+            Change::AddAddress(address) => {
+                exec_privileged(
+                    ctx,
+                    [
+                        "ip",
+                        family(address),
+                        "address",
+                        "add",
+                        address,
+                        "dev",
+                        network_name,
+                    ],
+                )?;
+            }
+            Change::RemoveAddress(address) => {
+                exec_privileged(
+                    ctx,
+                    [
+                        "ip",
+                        family(address),
+                        "address",
+                        "del",
+                        address,
+                        "dev",
+                        network_name,
+                    ],
+                )?;
+            }
+            Change::AddRoute(route) => {
+                exec_privileged(
+                    ctx,
+                    [
+                        "ip",
+                        family(route),
+                        "route",
+                        "replace",
+                        route,
+                        "dev",
+                        network_name,
+                    ],
+                )?;
+            }
+            Change::RemoveRoute(route) => {
+                exec_privileged(
+                    ctx,
+                    [
+                        "ip",
+                        family(route),
+                        "route",
+                        "del",
+                        route,
+                        "dev",
+                        network_name,
+                    ],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compute the changes between the running interface and the config, and
+/// apply them unless `diff_only`
+pub fn reconcile(
+    ctx: &Context,
+    network: &Network,
+    priv_id: PrivId,
+    phonebook: &Phonebook,
+    exit_node: Option<String>,
+    diff_only: bool,
+) -> Result<Vec<Change>, NetworkError> {
+    let network_name = get_wg_interface_name(&network.name);
+    let live = live_state(ctx, &network_name)?;
+    let desired = desired_state(network, priv_id, phonebook, exit_node);
+    let changes = diff(network, desired, &live);
+    if !diff_only {
+        apply(ctx, &network_name, &changes)?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{key, network};
+    use serde_json::json;
+
+    fn peer(allowed_ips: &[&str], endpoint: Option<&str>) -> PeerState {
+        PeerState {
+            name: None,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.to_string()).collect(),
+            endpoint: endpoint.map(str::to_string),
+        }
+    }
+
+    fn state(peers: &[(char, PeerState)], routes: &[&str]) -> InterfaceState {
+        InterfaceState {
+            public_key: None,
+            listen_port: None,
+            peers: peers
+                .iter()
+                .map(|(seed, peer)| (key(*seed), peer.clone()))
+                .collect(),
+            addresses: BTreeSet::from([String::from("10.0.0.4/32")]),
+            routes: routes.iter().map(|route| route.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_wg_conf_reads_port_and_peers() {
+        let (hub, bob) = (key('H'), key('B'));
+        let conf = format!(
+            "[Interface]\nPrivateKey = xxx\nListenPort = 51821\n\
+             # hub\n[Peer]\nPublicKey = {hub}\nAllowedIPs = 10.0.0.1/32, 10.0.0.0/16\n\
+             Endpoint = 192.0.2.1:51820\nPersistentKeepalive = 25\n\
+             # bob\n[Peer]\nPublicKey = {bob}\nAllowedIPs = 10.0.0.5/32, 192.168.5.1/24\n\
+             # hub\n[Peer]\nPublicKey = {hub}\nAllowedIPs = 10.0.0.1/32, fd00:1::/64"
+        );
+        let (listen_port, peers) = parse_wg_conf(&conf);
+        assert_eq!(listen_port, Some(51821));
+        assert_eq!(peers.len(), 2);
+        let hub = &peers[&hub];
+        assert_eq!(hub.name.as_deref(), Some("hub"));
+        assert_eq!(
+            hub.allowed_ips,
+            peer(&["10.0.0.0/16", "10.0.0.1/32", "fd00:1::/64"], None).allowed_ips
+        );
+        assert_eq!(hub.endpoint.as_deref(), Some("192.0.2.1:51820"));
+        let bob = &peers[&bob];
+        assert_eq!(bob.name.as_deref(), Some("bob"));
+        assert_eq!(
+            bob.allowed_ips,
+            peer(&["10.0.0.5/32", "192.168.5.0/24"], None).allowed_ips
+        );
+        assert_eq!(bob.endpoint, None);
+    }
+
+    #[test]
+    fn diff_of_identical_states_is_empty() {
+        let hub = peer(&["10.0.0.1/32", "10.0.0.0/16"], Some("192.0.2.1:51820"));
+        let desired = state(&[('H', hub.clone())], &["10.0.0.0/16"]);
+        let live = state(&[('H', hub)], &["10.0.0.0/16", "0.0.0.0/0"]);
+        assert!(diff(&network(json!({})), desired, &live).is_empty());
+    }
+
+    #[test]
+    fn diff_finds_every_change() {
+        let mut desired = state(
+            &[
+                ('H', peer(&["10.0.0.1/32"], Some("192.0.2.1:51820"))),
+                ('B', peer(&["10.0.0.5/32", "192.168.5.0/24"], None)),
+                ('C', peer(&["10.0.0.6/32"], None)),
+            ],
+            &["10.0.0.0/16", "192.168.5.0/24", "fd00:5::/64"],
+        );
+        desired.listen_port = Some(51820);
+        let mut live = state(
+            &[
+                ('H', peer(&["10.0.0.1/32"], Some("192.0.2.9:51820"))),
+                ('B', peer(&["10.0.0.5/32"], Some("198.51.100.7:40000"))),
+                ('I', peer(&["10.0.0.2/32"], None)),
+            ],
+            &["10.0.0.0/16", "192.168.9.0/24", "::/0"],
+        );
+        live.public_key = Some(key('C'));
+        live.addresses.insert(String::from("10.0.0.99/32"));
+        let changes: Vec<String> = diff(&network(json!({})), desired, &live)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                String::from("~ listen-port (none) -> 51820"),
+                format!(
+                    "~ peer {} allowed-ips 10.0.0.5/32 -> 10.0.0.5/32, 192.168.5.0/24",
+                    key('B')
+                ),
+                format!(
+                    "~ peer {} endpoint 192.0.2.9:51820 -> 192.0.2.1:51820",
+                    key('H')
+                ),
+                format!("- peer {}", key('I')),
+                String::from("- address 10.0.0.99/32"),
+                String::from("+ route 192.168.5.0/24"),
+                String::from("+ route fd00:5::/64"),
+                String::from("- route 192.168.9.0/24"),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_keeps_the_subnet_on_the_active_hub() {
+        let desired = state(
+            &[
+                ('H', peer(&["10.0.0.1/32", "10.0.0.0/16"], None)),
+                ('I', peer(&["10.0.0.2/32"], None)),
+            ],
+            &[],
+        );
+        let live = state(
+            &[
+                ('H', peer(&["10.0.0.1/32"], None)),
+                ('I', peer(&["10.0.0.2/32", "10.0.0.0/16"], None)),
+            ],
+            &[],
+        );
+        assert!(diff(&network(json!({})), desired, &live).is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use super::{phonebook::Phonebook, policy::ANY, Network, PrivateEndpoint};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/*
+ * Who sees whom in the phonebook
+ * The network's `visibility` rules say which groups (see policy.rs) the
+ * members of a group may see, "any" standing for everyone. When the hub
+ * serves the phonebook (see serve.rs), it knows the requester by their VPN
+ * IP and only sends them the users they may see, and themselves. Hubs see
+ * everyone. Without rules, everyone sees everyone.
+ * A user someone can't see is still reachable through the hub's route to the
+ * subnet (unless the policy says otherwise), but not their LAN `routes`.
+ */
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct VisibilityRule {
+    /// Group the rule is for, or "any"
+    pub group: String,
+    /// Groups its members see, or "any"
+    pub sees: Vec<String>,
+}
+
+fn in_group(user: &PrivateEndpoint, group: &str) -> bool {
+    group == ANY || user.groups.iter().any(|g| g == group)
+}
+
+/// The part of `phonebook` the user (or hub) at `requester_ip` may see, or
+/// None if it's neither
+pub fn visible_phonebook(
+    network: &Network,
+    phonebook: Phonebook,
+    requester_ip: &str,
+) -> Option<Phonebook> {
+    if network
+        .public_endpoints
+        .iter()
+        .any(|hub| hub.vpn_ip == requester_ip)
+    {
+        return Some(phonebook);
+    }
+    let requester = phonebook
+        .values()
+        .find(|user| user.vpn_ip == requester_ip)?
+        .clone();
+    if network.visibility.is_empty() {
+        return Some(phonebook);
+    }
+    let sees: Vec<&String> = network
+        .visibility
+        .iter()
+        .filter(|rule| in_group(&requester, &rule.group))
+        .flat_map(|rule| rule.sees.iter())
+        .collect();
+    Some(
+        phonebook
+            .into_iter()
+            .filter(|(_, user)| {
+                user.name == requester.name || sees.iter().any(|group| in_group(user, group))
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{network, phonebook};
+    use serde_json::{json, Value};
+
+    fn rules() -> Value {
+        json!([
+            { "group": "ops", "sees": ["any"] },
+            { "group": "guests", "sees": ["web"] }
+        ])
+    }
+
+    fn visible(visibility: Value, requester_ip: &str) -> Option<Vec<String>> {
+        visible_phonebook(
+            &network(json!({ "visibility": visibility })),
+            phonebook(),
+            requester_ip,
+        )
+        .map(|phonebook| phonebook.into_keys().collect())
+    }
+
+    #[test]
+    fn hubs_see_everyone() {
+        assert_eq!(
+            visible(rules(), "10.0.0.2").unwrap(),
+            ["bob", "carl", "dora", "janie"]
+        );
+    }
+
+    #[test]
+    fn strangers_see_nothing() {
+        assert_eq!(visible(rules(), "10.0.0.99"), None);
+        assert_eq!(visible(json!([]), "10.0.0.99"), None);
+    }
+
+    #[test]
+    fn without_rules_everyone_sees_everyone() {
+        assert_eq!(
+            visible(json!([]), "10.0.0.7").unwrap(),
+            ["bob", "carl", "dora", "janie"]
+        );
+    }
+
+    #[test]
+    fn rules_filter_by_group() {
+        assert_eq!(
+            visible(rules(), "10.0.0.4").unwrap(),
+            ["bob", "carl", "dora", "janie"]
+        );
+        assert_eq!(visible(rules(), "10.0.0.6").unwrap(), ["bob", "carl"]);
+        assert_eq!(visible(rules(), "10.0.0.5").unwrap(), ["bob"]);
+        assert_eq!(visible(rules(), "10.0.0.7").unwrap(), ["dora"]);
+    }
+}
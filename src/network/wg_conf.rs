@@ -1,4 +1,7 @@
-use super::{phonebook::Phonebook, Network, PrivateEndpoint, PublicEndpoint};
+use super::{
+    exit_node::fwmark, mtu::Mtu, peers::KEEPALIVE, phonebook::Phonebook, Network, PrivateEndpoint,
+    PublicEndpoint,
+};
 use crate::id::PrivId;
 
 pub trait WgConfSection<'a> {
@@ -10,8 +13,8 @@ impl<'a> WgConfSection<'a> for PublicEndpoint {
     type Input = &'a str;
     fn wg_conf_section(&self, subnet: &str) -> String {
         format!(
-            "# {}\n[Peer]\nPublicKey = {}\nAllowedIPs = {}\nEndpoint = {}:{}",
-            self.name, self.public_key, subnet, self.public_hostname, self.port
+            "# {}\n[Peer]\nPublicKey = {}\nAllowedIPs = {}\nEndpoint = {}:{}\nPersistentKeepalive = {}",
+            self.name, self.public_key, subnet, self.public_hostname, self.port, KEEPALIVE
         )
     }
 }
@@ -19,9 +22,14 @@ impl<'a> WgConfSection<'a> for PublicEndpoint {
 impl WgConfSection<'_> for PrivateEndpoint {
     type Input = ();
     fn wg_conf_section(&self, _: ()) -> String {
+        let allowed_ips = [format!("{}/32", self.vpn_ip)]
+            .into_iter()
+            .chain(self.routes.iter().cloned())
+            .collect::<Vec<String>>()
+            .join(", ");
         format!(
-            "# {}\n[Peer]\nPublicKey = {}\nAllowedIPs = {}/32",
-            self.name, self.public_key, self.vpn_ip
+            "# {}\n[Peer]\nPublicKey = {}\nAllowedIPs = {}",
+            self.name, self.public_key, allowed_ips
         )
     }
 }
@@ -30,6 +38,7 @@ pub struct NetworkWgConfInput {
     pub priv_id: PrivId,
     pub mobile: bool,
     pub port: Option<u64>,
+    pub exit_node: Option<String>,
 }
 
 impl<'a> WgConfSection<'a> for Network {
@@ -43,11 +52,35 @@ impl<'a> WgConfSection<'a> for Network {
         } else {
             format!("[Interface]\nPrivateKey = {}\n", &arg.priv_id.private_key)
         };
+        /*
+         * An "auto" MTU is left to the apps, which know the phone's network
+         */
+        if let (true, Some(Mtu::Fixed(mtu))) = (arg.mobile, self.mtu()) {
+            conf.push_str(&format!("MTU = {}\n", mtu));
+        }
         if arg.port.is_some() {
             conf.push_str(&format!("ListenPort = {}\n", arg.port.unwrap_or_default()));
         }
-        for endpoint in &self.public_endpoints[..] {
-            conf.push_str(&format!("{}", endpoint.wg_conf_section(&self.subnet)));
+        if !arg.mobile && arg.exit_node.is_some() {
+            conf.push_str(&format!(
+                "FwMark = {}\n",
+                fwmark(&get_wg_interface_name(&self.name))
+            ));
+        }
+        /*
+         * Every hub gets its own /32, but only the primary (the first one)
+         * gets the subnet (see failover.rs)
+         */
+        for (i, endpoint) in self.public_endpoints.iter().enumerate() {
+            let mut allowed_ips = vec![format!("{}/32", endpoint.vpn_ip)];
+            if i == 0 {
+                allowed_ips.push(self.subnet.clone());
+            }
+            if arg.exit_node.as_ref() == Some(&endpoint.name) {
+                allowed_ips.extend([String::from("0.0.0.0/0"), String::from("::/0")]);
+            }
+            conf.push_str(&endpoint.wg_conf_section(&allowed_ips.join(", ")));
+            conf.push('\n');
         }
         conf
     }
@@ -64,5 +97,6 @@ impl WgConfSection<'_> for Phonebook {
 }
 
 pub fn get_wg_interface_name(network_name: &str) -> String {
-    format!("tulip_{}", &network_name[..8])
+    let prefix: String = network_name.chars().take(8).collect();
+    format!("tulip_{}", prefix)
 }
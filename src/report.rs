@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/*
+ * Machine-readable results (--json)
+ * Commands record what they did in their Context's Reporter as they go: the
+ * files they wrote, the interface and peers they configured, the changes they
+ * made (or, with `sync --diff`, would make), what they had to do without
+ * (warnings), and what went wrong. With --json, the human messages (and the
+ * output of the commands tulip runs) go to stderr, and main prints a single
+ * Report on stdout when the command is done, or as soon as it panics. Either
+ * way, tulip exits with 1 if the Report has errors.
+ */
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Report {
+    pub command: String,
+    pub ok: bool,
+    pub written: Vec<String>,
+    pub interface: Option<String>,
+    pub peers: Vec<PeerReport>,
+    pub changes: Vec<String>,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerReport {
+    pub name: Option<String>,
+    pub public_key: String,
+    pub allowed_ips: Vec<String>,
+    pub endpoint: Option<String>,
+    pub latest_handshake: Option<u64>,
+}
+
+/// Where a Context's messages and results go. Clones share the same Report.
+#[derive(Debug, Clone, Default)]
+pub struct Reporter {
+    json: bool,
+    report: Arc<Mutex<Report>>,
+}
+
+impl Reporter {
+    pub fn new(json: bool, command: &str) -> Reporter {
+        Reporter {
+            json,
+            report: Arc::new(Mutex::new(Report {
+                command: command.to_string(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn with_report(&self, f: impl FnOnce(&mut Report)) {
+        f(&mut self.report.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    /// Tell the human running tulip, without getting in the way of the Report
+    pub fn say(&self, message: &str) {
+        if self.json {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    pub fn written(&self, path: &str) {
+        self.with_report(|report| report.written.push(path.to_string()));
+    }
+
+    pub fn interface(&self, name: &str) {
+        self.with_report(|report| report.interface = Some(name.to_string()));
+    }
+
+    pub fn peers(&self, peers: Vec<PeerReport>) {
+        self.with_report(|report| report.peers.extend(peers));
+    }
+
+    pub fn changes(&self, changes: Vec<String>) {
+        self.with_report(|report| report.changes.extend(changes));
+    }
+
+    /// Something went wrong, but the command could do without it
+    pub fn warning(&self, message: &str) {
+        eprintln!("{}", message);
+        self.with_report(|report| report.warnings.push(message.to_string()));
+    }
+
+    pub fn error(&self, message: &str) {
+        eprintln!("{}", message);
+        self.with_report(|report| report.errors.push(message.to_string()));
+    }
+
+    /// What was recorded so far
+    pub fn report(&self) -> Report {
+        let mut report = self
+            .report
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        report.ok = report.errors.is_empty();
+        report
+    }
+}
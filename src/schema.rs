@@ -0,0 +1,101 @@
+use crate::{
+    id::{PrivId, PubId},
+    network::{phonebook::Phonebook, Network},
+};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{ArrayValidation, InstanceType, RootSchema, Schema, SchemaObject, StringValidation},
+    schema_for,
+};
+
+/*
+ * JSON Schemas for tulip's files
+ * Derived from the structs they're read into (#[derive(JsonSchema)], with the
+ * fields' doc comments as descriptions), so that editors and CI can check a
+ * file before tulip reads it. The helpers below give the fields that are
+ * plain Strings on the Rust side their format or pattern.
+ */
+
+pub const KINDS: [&str; 4] = ["network", "phonebook", "private-id", "public-id"];
+
+pub fn schema(kind: &str) -> Option<RootSchema> {
+    match kind {
+        "network" => Some(schema_for!(Network)),
+        "phonebook" => Some(schema_for!(Phonebook)),
+        "private-id" => Some(schema_for!(PrivId)),
+        "public-id" => Some(schema_for!(PubId)),
+        _ => None,
+    }
+}
+
+const CIDR: &str = r"^[0-9A-Fa-f.:]+/[0-9]{1,3}$";
+const HOST_PORT: &str = r"^(\[[0-9A-Fa-f:.]+\]|[^:\s]+):[0-9]{1,5}$";
+const WG_KEY: &str = r"^[A-Za-z0-9+/]{42}[AEIMQUYcgkosw480]=$";
+const SHA256: &str = r"^([0-9A-Fa-f]{2}:?){31}[0-9A-Fa-f]{2}$";
+
+fn string(format: Option<&str>, pattern: Option<&str>) -> SchemaObject {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        format: format.map(String::from),
+        string: pattern.map(|pattern| {
+            Box::new(StringValidation {
+                pattern: Some(pattern.to_string()),
+                ..Default::default()
+            })
+        }),
+        ..Default::default()
+    }
+}
+
+fn array_of(items: SchemaObject) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::Object(items).into()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+pub fn ipv4(_: &mut SchemaGenerator) -> Schema {
+    string(Some("ipv4"), None).into()
+}
+
+pub fn cidr(_: &mut SchemaGenerator) -> Schema {
+    string(None, Some(CIDR)).into()
+}
+
+pub fn cidrs(_: &mut SchemaGenerator) -> Schema {
+    array_of(string(None, Some(CIDR)))
+}
+
+pub fn host_ports(_: &mut SchemaGenerator) -> Schema {
+    array_of(string(None, Some(HOST_PORT)))
+}
+
+pub fn host_port(_: &mut SchemaGenerator) -> Schema {
+    string(None, Some(HOST_PORT)).into()
+}
+
+pub fn wg_key(_: &mut SchemaGenerator) -> Schema {
+    string(None, Some(WG_KEY)).into()
+}
+
+pub fn uri(_: &mut SchemaGenerator) -> Schema {
+    string(Some("uri"), None).into()
+}
+
+pub fn sha256(_: &mut SchemaGenerator) -> Schema {
+    string(None, Some(SHA256)).into()
+}
+
+pub fn scheme(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(vec!["http".into(), "https".into()]),
+        ..Default::default()
+    }
+    .into()
+}
@@ -0,0 +1,182 @@
+use crate::{
+    context::Context,
+    invite::{self, EnrollRequest, InviteError},
+    network::{self, phonebook, revocation, visibility::visible_phonebook, NetworkError},
+};
+use std::{io::Read, path::Path, sync::mpsc, thread};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/*
+ * The hub's HTTP endpoint
+ * - POST /enroll: redeem an invite (see invite.rs)
+ * - GET /phonebook.json: the phonebook (or the dynamic one, if given), as
+ *    the requester may see it (see visibility.rs). The requester is whoever
+ *    has the request's source IP in the network, so this is for clients
+ *    coming through the tunnel, not through a proxy.
+ * - GET /revocations.json: the revoked keys, if revocations.json exists
+ * It can listen on several addresses at once, typically one behind the HTTPS
+ * proxy users enroll through, and the hub's VPN IP for the phonebook.
+ */
+
+/*
+ * An EnrollRequest is a secret and a public key, well under this
+ */
+const MAX_BODY: u64 = 4096;
+
+pub struct ServeInput {
+    pub listen: Vec<String>,
+    pub network_path: String,
+    pub phonebook_path: String,
+    pub invites_path: String,
+    pub revocations_path: String,
+    pub dynamic_phonebook_path: Option<String>,
+}
+
+fn respond(ctx: &Context, request: Request, status: u16, body: String, json: bool) {
+    let mut response = Response::from_string(body).with_status_code(status);
+    if json {
+        response = response.with_header(
+            Header::from_bytes("Content-Type", "application/json").expect("header issue"),
+        );
+    }
+    if let Err(e) = request.respond(response) {
+        ctx.report.error(&format!("couldn't respond: {e}"));
+    }
+}
+
+fn status_code(e: &InviteError) -> u16 {
+    match e {
+        InviteError::BadRequest(_) => 400,
+        InviteError::BadToken(_) => 403,
+        InviteError::Conflict(_) => 409,
+        InviteError::NoFreeIp(_) => 503,
+        InviteError::Serde(_) => 400,
+        _ => 500,
+    }
+}
+
+fn handle_enroll(
+    ctx: &Context,
+    input: &ServeInput,
+    request: &mut Request,
+) -> Result<String, InviteError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_string(&mut body)?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(InviteError::BadRequest(String::from("request too large")));
+    }
+    let enroll_request: EnrollRequest = serde_json::from_str(&body)?;
+    let network = invite::enroll(
+        ctx,
+        &input.network_path,
+        &input.phonebook_path,
+        &input.invites_path,
+        &input.revocations_path,
+        &enroll_request,
+    )?;
+    ctx.report.say(&format!(
+        "enrolled {} as {}",
+        &network.user.name, &network.user.vpn_ip
+    ));
+    Ok(serde_json::to_string_pretty(&network)?)
+}
+
+/*
+ * The phonebook for the user (or hub) at `requester_ip`, or None if they
+ * aren't in the network
+ */
+fn handle_phonebook(
+    ctx: &Context,
+    input: &ServeInput,
+    requester_ip: &str,
+) -> Result<Option<String>, NetworkError> {
+    let network = network::read_network_file(ctx, &input.network_path)?;
+    let path = input
+        .dynamic_phonebook_path
+        .as_ref()
+        .unwrap_or(&input.phonebook_path);
+    let phonebook = phonebook::read_phonebook_file(ctx, path.clone())?;
+    match visible_phonebook(&network, phonebook, requester_ip) {
+        Some(phonebook) => Ok(Some(serde_json::to_string_pretty(&phonebook)?)),
+        None => Ok(None),
+    }
+}
+
+fn handle_revocations(input: &ServeInput) -> Result<Option<String>, NetworkError> {
+    /*
+     * Without revocations.json, we don't know of any revocations, which isn't
+     * the same as knowing there are none (see revocation.rs)
+     */
+    if !Path::new(&input.revocations_path).exists() {
+        return Ok(None);
+    }
+    let revocations = revocation::read_revocations_file(&input.revocations_path)?;
+    Ok(Some(serde_json::to_string_pretty(&revocations)?))
+}
+
+/// Answer requests on the `input.listen` addresses (127.0.0.1:8443 by default)
+/// until killed.
+/// Requests are handled one at a time, whichever address they came in on, so
+/// phonebook.json and invites.json are never written concurrently.
+pub fn serve(ctx: &Context, input: &ServeInput) -> Result<(), std::io::Error> {
+    let (tx, rx) = mpsc::channel();
+    for listen in input.listen.iter() {
+        let server = Server::http(listen).map_err(std::io::Error::other)?;
+        ctx.report.say(&format!("listening on {}", listen));
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if tx.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+    for mut request in rx.iter() {
+        match (request.method(), request.url()) {
+            (Method::Post, "/enroll") => match handle_enroll(ctx, input, &mut request) {
+                Ok(body) => respond(ctx, request, 200, body, true),
+                Err(e) => {
+                    ctx.report.error(&format!("enrollment failed: {e}"));
+                    respond(ctx, request, status_code(&e), e.to_string(), false);
+                }
+            },
+            (Method::Get, "/phonebook.json") => {
+                let requester_ip = request
+                    .remote_addr()
+                    .map(|addr| addr.ip().to_canonical().to_string())
+                    .unwrap_or_default();
+                match handle_phonebook(ctx, input, &requester_ip) {
+                    Ok(Some(body)) => respond(ctx, request, 200, body, true),
+                    Ok(None) => respond(
+                        ctx,
+                        request,
+                        403,
+                        String::from("not in this network"),
+                        false,
+                    ),
+                    Err(e) => {
+                        ctx.report
+                            .error(&format!("serving the phonebook failed: {e}"));
+                        respond(ctx, request, 500, e.to_string(), false);
+                    }
+                }
+            }
+            (Method::Get, "/revocations.json") => match handle_revocations(input) {
+                Ok(Some(body)) => respond(ctx, request, 200, body, true),
+                Ok(None) => respond(ctx, request, 404, String::from("not found"), false),
+                Err(e) => {
+                    ctx.report
+                        .error(&format!("serving the revocations failed: {e}"));
+                    respond(ctx, request, 500, e.to_string(), false);
+                }
+            },
+            _ => respond(ctx, request, 404, String::from("not found"), false),
+        }
+    }
+    Ok(())
+}
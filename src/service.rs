@@ -0,0 +1,214 @@
+use crate::{
+    context::Context,
+    misc::{create_private_dir, create_private_file, exec_privileged},
+};
+use std::{fs, io::Write};
+
+/*
+ * systemd units for bringing a Tulip network up at boot.
+ * - tulip@.service: a oneshot unit running `tulip start`/`tulip stop` for the
+ *    network named by its instance, configured through /etc/tulip/<instance>.env
+ * - tulip-refresh@.service and tulip-refresh@.timer: an optional periodic
+ *    `tulip watch` that re-reads the network's phonebook
+ */
+
+pub struct ServiceInput {
+    pub tulip_bin: String,
+    pub instance: String,
+    pub network_path: String,
+    pub priv_id_path: String,
+    pub phonebook_path: Option<String>,
+    pub revocations_path: Option<String>,
+    pub server: bool,
+    pub timeout: u64,
+    pub refresh: Option<u64>,
+}
+
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+pub const ENV_DIR: &str = "/etc/tulip";
+
+pub fn service_unit(tulip_bin: &str) -> String {
+    format!(
+        "[Unit]
+Description=Tulip network %i
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+EnvironmentFile={ENV_DIR}/%i.env
+ExecStart={tulip_bin} start --network ${{TULIP_NETWORK}} --priv-id ${{TULIP_PRIV_ID}} $TULIP_START_ARGS
+ExecStop={tulip_bin} stop --network ${{TULIP_NETWORK}}
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+pub fn refresh_unit(tulip_bin: &str) -> String {
+    format!(
+        "[Unit]
+Description=Refresh the phonebook of Tulip network %i
+Requisite=tulip@%i.service
+After=tulip@%i.service
+
+[Service]
+Type=oneshot
+EnvironmentFile={ENV_DIR}/%i.env
+ExecStart={tulip_bin} watch --network ${{TULIP_NETWORK}} $TULIP_WATCH_ARGS
+"
+    )
+}
+
+pub fn refresh_timer() -> String {
+    String::from(
+        "[Unit]
+Description=Periodically refresh the phonebook of Tulip network %i
+PartOf=tulip@%i.service
+
+[Timer]
+OnActiveSec=1min
+OnUnitActiveSec=5min
+
+[Install]
+WantedBy=timers.target
+",
+    )
+}
+
+/// Drop-in overriding the refresh timer's default interval for one instance
+pub fn refresh_timer_interval(seconds: u64) -> String {
+    format!("[Timer]\nOnUnitActiveSec=\nOnUnitActiveSec={seconds}s\n")
+}
+
+/*
+ * A value for an EnvironmentFile, which systemd unquotes like a shell would
+ */
+fn env_value(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        if ['\\', '"', '$', '`'].contains(&c) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res.push('"');
+    res
+}
+
+/// The paths get variables of their own, which start and watch read (see
+/// main.rs), rather than being split out of TULIP_START_ARGS, so that they
+/// can have spaces and quotes
+pub fn env_file(input: &ServiceInput) -> String {
+    let mut vars = vec![
+        ("TULIP_NETWORK", input.network_path.clone()),
+        ("TULIP_PRIV_ID", input.priv_id_path.clone()),
+    ];
+    let mut start_args = format!("--timeout {}", input.timeout);
+    let watch_args = format!("--timeout {}", input.timeout);
+    /*
+     * The phonebook is what puts watch in server mode
+     */
+    if input.server {
+        start_args.push_str(" --server");
+        if let Some(phonebook) = &input.phonebook_path {
+            vars.push(("TULIP_PHONEBOOK", phonebook.clone()));
+        }
+    }
+    if let Some(revocations) = &input.revocations_path {
+        vars.push(("TULIP_REVOCATIONS", revocations.clone()));
+    }
+    vars.push(("TULIP_START_ARGS", start_args));
+    vars.push(("TULIP_WATCH_ARGS", watch_args));
+    vars.iter()
+        .map(|(name, value)| format!("{}={}\n", name, env_value(value)))
+        .collect()
+}
+
+/// Write the unit templates and the instance's environment file, then reload
+/// systemd. If `enable` is set, also enable and start the instance (and its
+/// refresh timer, if any).
+pub fn install(ctx: &Context, input: &ServiceInput, enable: bool) -> Result<(), std::io::Error> {
+    let instance = &input.instance;
+    let mut files = vec![
+        (
+            String::from("tulip@.service"),
+            format!("{UNIT_DIR}/tulip@.service"),
+            service_unit(&input.tulip_bin),
+        ),
+        (
+            format!("{instance}.env"),
+            format!("{ENV_DIR}/{instance}.env"),
+            env_file(input),
+        ),
+    ];
+    if let Some(seconds) = input.refresh {
+        files.push((
+            String::from("tulip-refresh@.service"),
+            format!("{UNIT_DIR}/tulip-refresh@.service"),
+            refresh_unit(&input.tulip_bin),
+        ));
+        files.push((
+            String::from("tulip-refresh@.timer"),
+            format!("{UNIT_DIR}/tulip-refresh@.timer"),
+            refresh_timer(),
+        ));
+        files.push((
+            String::from("interval.conf"),
+            format!("{UNIT_DIR}/tulip-refresh@{instance}.timer.d/interval.conf"),
+            refresh_timer_interval(seconds),
+        ));
+    }
+    /*
+     * install copies the files as root, so they're staged where no one else
+     * can swap them
+     */
+    let staging = create_private_dir(&format!("tulip_{}_units", instance))?;
+    let installed = files.iter().try_for_each(|(name, dest, contents)| {
+        let src = staging.join(name);
+        let mut file = create_private_file(&src)?;
+        write!(file, "{}", contents)?;
+        ctx.report.say(&format!("installing {}", dest));
+        exec_privileged(
+            ctx,
+            ["install", "-D", "-m", "0644", &src.to_string_lossy(), dest],
+        )
+    });
+    let _ = fs::remove_dir_all(&staging);
+    installed?;
+    exec_privileged(ctx, ["systemctl", "daemon-reload"])?;
+    if enable {
+        exec_privileged(
+            ctx,
+            [
+                "systemctl",
+                "enable",
+                "--now",
+                &format!("tulip@{instance}.service"),
+            ],
+        )?;
+        if input.refresh.is_some() {
+            exec_privileged(
+                ctx,
+                [
+                    "systemctl",
+                    "enable",
+                    "--now",
+                    &format!("tulip-refresh@{instance}.timer"),
+                ],
+            )?;
+        }
+    } else {
+        ctx.report.say(&format!(
+            "enable with: sudo systemctl enable --now tulip@{instance}.service"
+        ));
+        if input.refresh.is_some() {
+            ctx.report.say(&format!(
+                "and: sudo systemctl enable --now tulip-refresh@{instance}.timer"
+            ));
+        }
+    }
+    Ok(())
+}
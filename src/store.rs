@@ -0,0 +1,175 @@
+use crate::{
+    context::Context,
+    id::{self, IdError},
+    misc::create_private_file,
+    network::{self, phonebook, NetworkError},
+};
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::Write,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+};
+
+/*
+ * The named network store
+ * Each network lives in $XDG_CONFIG_HOME/tulip/networks/<name>/ (or
+ * ~/.config/tulip/networks/<name>/) and holds:
+ * - tulip_network.json: the network config
+ * - private_id.json: the user's private ID for this network (optional)
+ * - phonebook.json: a cached phonebook (optional)
+ * - phonebook_cache.json and revocations_cache.json: the last phonebook and
+ *    revocations curled from a hub (see network/cache.rs), kept even for
+ *    networks that weren't imported
+ * - state.json: what the running network changed outside its interface (see
+ *    network/state.rs)
+ */
+
+pub const NETWORK_FILE: &str = "tulip_network.json";
+pub const PRIV_ID_FILE: &str = "private_id.json";
+pub const PHONEBOOK_FILE: &str = "phonebook.json";
+
+#[derive(Debug)]
+pub enum StoreError {
+    FileIO(std::io::Error),
+    Id(IdError),
+    Network(NetworkError),
+    NoConfigDir(String),
+    NoSuchNetwork(String),
+    PrivIdExists(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::FileIO(e) => write!(f, "file error: {e}"),
+            StoreError::Id(e) => write!(f, "{e}"),
+            StoreError::Network(e) => write!(f, "{e}"),
+            StoreError::NoConfigDir(msg) => write!(f, "{msg}"),
+            StoreError::NoSuchNetwork(name) => write!(f, "no network named {name}"),
+            StoreError::PrivIdExists(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::FileIO(e)
+    }
+}
+
+impl From<IdError> for StoreError {
+    fn from(e: IdError) -> Self {
+        StoreError::Id(e)
+    }
+}
+
+impl From<NetworkError> for StoreError {
+    fn from(e: NetworkError) -> Self {
+        StoreError::Network(e)
+    }
+}
+
+/// Paths to the files making up one network
+#[derive(Debug)]
+pub struct NetworkPaths {
+    pub network: String,
+    pub priv_id: Option<String>,
+    pub phonebook: Option<String>,
+}
+
+pub fn config_dir() -> Result<PathBuf, StoreError> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(Path::new(&dir).join("tulip")),
+        None => match env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+            Some(home) => Ok(Path::new(&home).join(".config").join("tulip")),
+            None => Err(StoreError::NoConfigDir(String::from(
+                "neither $XDG_CONFIG_HOME nor $HOME is set",
+            ))),
+        },
+    }
+}
+
+pub fn network_dir(name: &str) -> Result<PathBuf, StoreError> {
+    Ok(config_dir()?.join("networks").join(name))
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// Look up a named network. The private ID and phonebook are only returned
+/// if they were imported.
+pub fn network_paths(name: &str) -> Result<NetworkPaths, StoreError> {
+    let dir = network_dir(name)?;
+    let network = dir.join(NETWORK_FILE);
+    if !network.exists() {
+        return Err(StoreError::NoSuchNetwork(name.to_string()));
+    }
+    let priv_id = dir.join(PRIV_ID_FILE);
+    let phonebook = dir.join(PHONEBOOK_FILE);
+    Ok(NetworkPaths {
+        network: path_string(network),
+        priv_id: priv_id.exists().then(|| path_string(priv_id)),
+        phonebook: phonebook.exists().then(|| path_string(phonebook)),
+    })
+}
+
+pub fn list() -> Result<Vec<String>, StoreError> {
+    let dir = config_dir()?.join("networks");
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().join(NETWORK_FILE).exists() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn copy_private(from: &str, to: &Path) -> Result<(), StoreError> {
+    let contents = fs::read_to_string(from)?;
+    let mut file = create_private_file(to)?;
+    write!(file, "{}", contents)?;
+    Ok(())
+}
+
+/// Copy a tulip_network.json (and optionally a private_id.json and
+/// phonebook.json) into the store, under the network's name.
+/// An existing private ID is only replaced if `force` is set.
+/// TOML and YAML files (see network/format.rs) are stored as JSON.
+pub fn import(
+    ctx: &Context,
+    network_path: &str,
+    priv_id_path: Option<&str>,
+    phonebook_path: Option<&str>,
+    force: bool,
+) -> Result<String, StoreError> {
+    let network = network::read_network_file(ctx, network_path)?;
+    let dir = network_dir(&network.name)?;
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    network::write_network_file(ctx, dir.join(NETWORK_FILE), &network)?;
+    if let Some(path) = priv_id_path {
+        id::read_id_file(path)?;
+        let dest = dir.join(PRIV_ID_FILE);
+        if dest.exists() && !force {
+            return Err(StoreError::PrivIdExists(format!(
+                "i won't overwrite {} without --force",
+                dest.display()
+            )));
+        }
+        copy_private(path, &dest)?;
+    }
+    if let Some(path) = phonebook_path {
+        let phonebook = phonebook::read_phonebook_file(ctx, path.to_string())?;
+        phonebook::write_phonebook_file(ctx, dir.join(PHONEBOOK_FILE), &phonebook)?;
+    }
+    Ok(network.name)
+}
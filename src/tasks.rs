@@ -1,14 +1,29 @@
 use qrcode_generator::QrCodeEcc;
 
-use crate::{
-    id,
-    misc::{create_private_file, exec_silent, set_kernel_parameter},
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tulip_net::{
+    context::Context,
+    id, invite,
+    misc::{self, create_private_file, exec_silent, set_kernel_parameter},
     network::{
-        self, phonebook,
+        self,
+        format::Format,
+        phonebook,
+        revocation::{self, Revocation, Revocations},
         wg_conf::{NetworkWgConfInput, WgConfSection},
+        Network, StartInput, SyncInput, UserEndpoint,
     },
+    schema,
+    serve::{self, ServeInput},
+    service::{self, ServiceInput},
+    store::{self, NetworkPaths},
 };
-use std::{io::Write, path::Path};
 
 /*
  * Public task functions
@@ -33,53 +48,377 @@ pub fn debug(onoff: Option<String>) {
     }
 }
 
-pub fn gen_id(name: String) {
-    id::gen_id_files(name).expect("gen_id problem");
+/*
+ * Resolve a network's file paths from its name in the network store,
+ * letting explicitly passed paths take precedence. The stored phonebook is
+ * only for hubs (`server`): a phonebook puts watch and sync in server mode.
+ */
+pub fn resolve_paths(
+    name: Option<String>,
+    network_path: Option<String>,
+    priv_id_path: Option<String>,
+    phonebook_path: Option<String>,
+    server: bool,
+) -> NetworkPaths {
+    let stored = name.map(|name| store::network_paths(&name).expect("network store problem"));
+    let network = network_path
+        .or_else(|| stored.as_ref().map(|paths| paths.network.clone()))
+        .expect("need a network name or a --network path");
+    NetworkPaths {
+        network,
+        priv_id: priv_id_path.or_else(|| stored.as_ref().and_then(|p| p.priv_id.clone())),
+        phonebook: phonebook_path.or_else(|| {
+            stored
+                .as_ref()
+                .filter(|_| server)
+                .and_then(|p| p.phonebook.clone())
+        }),
+    }
+}
+
+pub fn list_networks(ctx: &Context) {
+    for name in store::list().expect("network store problem") {
+        ctx.report.say(&name);
+    }
+}
+
+pub fn import_network(
+    ctx: &Context,
+    network_path: String,
+    priv_id_path: Option<String>,
+    phonebook_path: Option<String>,
+    force: bool,
+) {
+    let name = store::import(
+        ctx,
+        &network_path,
+        priv_id_path.as_deref(),
+        phonebook_path.as_deref(),
+        force,
+    )
+    .expect("network import problem");
+    let dir = store::network_dir(&name).expect("network store problem");
+    ctx.report
+        .say(&format!("imported {} to {}", &name, dir.display()));
+}
+
+pub fn gen_id(ctx: &Context, name: String) {
+    id::gen_id_files(ctx, name).expect("gen_id problem");
+}
+
+pub fn rotate_id(ctx: &Context, network_path: String, priv_id_path: String) {
+    let network =
+        network::read_network_file(ctx, &network_path).expect("reading network file problem");
+    let priv_id = id::read_id_file(&priv_id_path).expect("priv-id problem");
+    let endpoint_public_keys: Vec<&str> = network
+        .public_endpoints
+        .iter()
+        .map(|endpoint| endpoint.public_key.as_str())
+        .collect();
+    id::rotate_id_files(ctx, &priv_id, &endpoint_public_keys).expect("rotate id problem");
+}
+
+pub fn accept_rotation(
+    ctx: &Context,
+    request_path: String,
+    phonebook_path: String,
+    priv_id_path: String,
+) {
+    let request = id::read_rotation_request_file(&request_path).expect("rotation request problem");
+    let endpoint = id::read_id_file(&priv_id_path).expect("priv-id problem");
+    id::rotation::verify_rotation_request(&request, &endpoint).expect("rotation request problem");
+    let mut phonebook = phonebook::read_phonebook_file(ctx, phonebook_path.clone())
+        .expect("reading phonebook file problem");
+    match phonebook.get_mut(&request.name) {
+        Some(user) if user.public_key == request.old_public_key => {
+            user.public_key = request.new_public_key.clone();
+            phonebook::write_phonebook_file(ctx, &phonebook_path, &phonebook)
+                .expect("writing phonebook file problem");
+            ctx.report.say(&format!(
+                "rotated {}'s key to {} in {}",
+                &request.name, &request.new_public_key, &phonebook_path
+            ));
+        }
+        Some(_) => {
+            panic!(
+                "{}'s public key in the phonebook isn't the one being rotated",
+                &request.name
+            );
+        }
+        None => {
+            panic!("{} is not a user", &request.name);
+        }
+    }
+}
+
+pub fn create_invite(ctx: &Context, invites_path: String, name: String, url: String, ttl: u64) {
+    let token =
+        invite::create_invite(&invites_path, &name, &url, ttl).expect("create invite problem");
+    ctx.report.say(&token);
+}
+
+pub fn join(ctx: &Context, token: String) {
+    let (network_path, priv_id_path) = invite::join(ctx, &token).expect("join problem");
+    ctx.report.say(&format!(
+        "joined! import it with: tulip networks import {network_path} --priv-id {priv_id_path}"
+    ));
+}
+
+pub fn serve(
+    ctx: &Context,
+    listen: Vec<String>,
+    network_path: String,
+    phonebook_path: String,
+    invites_path: String,
+    revocations_path: String,
+    dynamic_phonebook_path: Option<String>,
+) {
+    /*
+     * Enrolling a user adds them to the running interface
+     */
+    misc::check_privileges(ctx, false).expect("privileges problem");
+    serve::serve(
+        ctx,
+        &ServeInput {
+            listen,
+            network_path,
+            phonebook_path,
+            invites_path,
+            revocations_path,
+            dynamic_phonebook_path,
+        },
+    )
+    .expect("serve problem");
+}
+
+/*
+ * In server mode, revocations come from a local revocations.json (if any);
+ * otherwise they are curled along with the phonebook
+ */
+fn read_revocations(server: bool, revocations_path: &Option<String>) -> Option<Revocations> {
+    match (server, revocations_path) {
+        (_, Some(path)) => {
+            Some(revocation::read_revocations_file(path).expect("reading revocations problem"))
+        }
+        (true, None) => Some(Revocations::new()),
+        (false, None) => None,
+    }
+}
+
+pub fn schema(ctx: &Context, kind: &str) {
+    let schema = schema::schema(kind).expect("unknown schema");
+    ctx.report
+        .say(&serde_json::to_string_pretty(&schema).expect("json issue"));
+}
+
+pub fn revoke(
+    ctx: &Context,
+    name: String,
+    phonebook_path: String,
+    revocations_path: String,
+    reason: String,
+) {
+    let mut phonebook = phonebook::read_phonebook_file(ctx, phonebook_path.clone())
+        .expect("reading phonebook file problem");
+    let mut revocations =
+        revocation::read_revocations_file(&revocations_path).expect("reading revocations problem");
+    match phonebook.remove(&name) {
+        Some(user) => {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            revocations.push(Revocation {
+                name: name.clone(),
+                public_key: user.public_key.clone(),
+                reason,
+                timestamp,
+            });
+            revocation::write_revocations_file(&revocations_path, &revocations)
+                .expect("writing revocations problem");
+            phonebook::write_phonebook_file(ctx, &phonebook_path, &phonebook)
+                .expect("writing phonebook file problem");
+            ctx.report.say(&format!(
+                "revoked {} ({}) in {} and removed them from {}",
+                &name, &user.public_key, &revocations_path, &phonebook_path
+            ));
+        }
+        None => {
+            panic!("{} is not a user", &name);
+        }
+    }
+}
+
+/*
+ * Read a network file, letting the Context's `netns` (--netns) take precedence
+ * over the file's (network::start and friends run the networking commands in
+ * it)
+ */
+fn read_network(ctx: &Context, network_path: &str) -> Network {
+    let mut network =
+        network::read_network_file(ctx, network_path).expect("reading network file problem");
+    network.netns = ctx.netns.clone().or(network.netns);
+    network
 }
 
 pub fn start_network(
+    ctx: &Context,
     network_path: String,
     priv_id_path: String,
-    server: bool,
     phonebook_path: Option<String>,
-    timeout: u64,
+    revocations_path: Option<String>,
+    input: StartInput,
 ) {
-    let network = network::read_network_file(&network_path).expect("reading network file problem");
+    let network = read_network(ctx, &network_path);
     let priv_id = id::read_id_file(&priv_id_path).expect("priv-id problem");
-    let phonebook = if server {
-        let path = network::phonebook::read_phonebook_file(phonebook_path.unwrap_or_default())
+    let phonebook = if input.server {
+        let path = network::phonebook::read_phonebook_file(ctx, phonebook_path.unwrap_or_default())
             .expect("reading phonebook file problem");
         Some(path)
     } else {
         None
     };
-    network::start(network, priv_id, server, phonebook, timeout).expect("start network problem");
+    let revocations = read_revocations(input.server, &revocations_path);
+    network::start(ctx, network, priv_id, phonebook, revocations, &input)
+        .expect("start network problem");
+}
+
+pub fn stop_network(ctx: &Context, network_path: String) {
+    let network = read_network(ctx, &network_path);
+    network::stop(ctx, network).expect("stop network problem");
+}
+
+pub fn status_network(ctx: &Context, network_path: String) {
+    let network = read_network(ctx, &network_path);
+    network::check_netns(&network).expect("netns problem");
+    network::status(ctx, &network).expect("network status problem");
+}
+
+pub fn sync_network(
+    ctx: &Context,
+    paths: NetworkPaths,
+    priv_id_path: String,
+    revocations_path: Option<String>,
+    input: SyncInput,
+) {
+    let network = read_network(ctx, &paths.network);
+    let priv_id = id::read_id_file(&priv_id_path).expect("priv-id problem");
+    let phonebook = paths.phonebook.map(|path| {
+        phonebook::read_phonebook_file(ctx, path).expect("reading phonebook file problem")
+    });
+    let revocations = read_revocations(phonebook.is_some(), &revocations_path);
+    network::sync(ctx, &network, priv_id, phonebook, revocations, input)
+        .expect("sync network problem");
 }
 
-pub fn stop_network(network_path: String) {
-    let network = network::read_network_file(&network_path).expect("reading network file problem");
-    network::stop(network).expect("stop network problem");
+pub fn watch_network(
+    ctx: &Context,
+    paths: NetworkPaths,
+    revocations_path: Option<String>,
+    dynamic_phonebook_path: Option<String>,
+    mesh: bool,
+    timeout: u64,
+    interval: Option<u64>,
+) {
+    let phonebook_path = paths.phonebook;
+    let network = read_network(ctx, &paths.network);
+    loop {
+        let revocations = read_revocations(phonebook_path.is_some(), &revocations_path);
+        let res = match &phonebook_path {
+            Some(path) => phonebook::read_phonebook_file(ctx, path.clone()).and_then(|p| {
+                network::refresh(
+                    ctx,
+                    &network,
+                    Some(p),
+                    revocations,
+                    mesh,
+                    timeout,
+                    dynamic_phonebook_path.as_deref(),
+                )
+            }),
+            None => network::refresh(ctx, &network, None, revocations, mesh, timeout, None),
+        };
+        match (res, interval) {
+            (Ok(()), None) => break,
+            (Err(e), None) => panic!("refresh network problem: {e}"),
+            (Ok(()), Some(n)) => sleep(Duration::from_secs(n)),
+            (Err(e), Some(n)) => {
+                ctx.report.error(&format!("refresh network problem: {e}"));
+                sleep(Duration::from_secs(n));
+            }
+        }
+    }
 }
 
-pub fn write_network_json_file(
+pub fn install_service(
+    ctx: &Context,
+    paths: NetworkPaths,
+    revocations_path: Option<String>,
+    server: bool,
+    timeout: u64,
+    refresh: Option<u64>,
+    enable: bool,
+) {
+    misc::check_privileges(ctx, true).expect("privileges problem");
+    let network =
+        network::read_network_file(ctx, &paths.network).expect("reading network file problem");
+    let priv_id_path = paths.priv_id.expect("need a --priv-id");
+    let absolute = |path: &str| -> String {
+        fs::canonicalize(path)
+            .expect("couldn't resolve path")
+            .to_str()
+            .expect("path concat issue")
+            .to_string()
+    };
+    let tulip_bin = std::env::current_exe().expect("couldn't locate the tulip binary");
+    let input = ServiceInput {
+        tulip_bin: tulip_bin.to_str().expect("path concat issue").to_string(),
+        instance: network.name.clone(),
+        network_path: absolute(&paths.network),
+        priv_id_path: absolute(&priv_id_path),
+        phonebook_path: paths.phonebook.as_deref().map(absolute),
+        revocations_path: revocations_path.as_deref().map(absolute),
+        server,
+        timeout,
+        refresh,
+    };
+    service::install(ctx, &input, enable).expect("install service problem");
+}
+
+/*
+ * The user's network file is written in the same format as `network_path`
+ */
+pub fn write_network_conf_file(
+    ctx: &Context,
     out_dir: String,
     name: String,
     network_path: String,
     phonebook_path: String,
 ) {
     let phonebook =
-        phonebook::read_phonebook_file(phonebook_path).expect("reading public_id.json issue");
+        phonebook::read_phonebook_file(ctx, phonebook_path).expect("reading public_id.json issue");
     match phonebook.get(&name) {
         Some(user) => {
-            let mut net_conf =
-                network::read_network_file(&network_path).expect("reading network file problem");
-            net_conf.user.name = name.clone();
-            net_conf.user.vpn_ip = user.vpn_ip.clone();
-            let net_conf_json = serde_json::to_string_pretty(&net_conf).expect("json issue");
-            let out_path_aux = Path::new(&out_dir).join(format!("{}_tulip_network.json", &name));
+            let mut net_conf = network::read_network_file(ctx, &network_path)
+                .expect("reading network file problem");
+            net_conf.user = UserEndpoint {
+                name: name.clone(),
+                vpn_ip: user.vpn_ip.clone(),
+                port: None,
+                mtu: None,
+            };
+            // the policy and visibility rules are enforced by the hubs,
+            // users don't need them, and the hub's namespace is its own
+            net_conf.policy.clear();
+            net_conf.visibility.clear();
+            net_conf.netns = None;
+            let extension = Format::of(&network_path, ctx.format).extension();
+            let out_path_aux =
+                Path::new(&out_dir).join(format!("{}_tulip_network.{}", &name, extension));
             let out_path = out_path_aux.to_str().expect("path concat issue");
-            let out_file = create_private_file(&out_path).expect("couldn't create output file");
-            writeln!(&out_file, "{}", &net_conf_json).expect("couldn't write the json file");
+            network::write_network_file(ctx, out_path, &net_conf)
+                .expect("couldn't write the network file");
+            ctx.report.written(out_path);
         }
         None => {
             panic!("{} is not a user", &name);
@@ -87,22 +426,39 @@ pub fn write_network_json_file(
     }
 }
 
-pub fn write_wg_conf_file(kind: &str, out_dir: &str, network_path: &str, priv_id_path: &str) {
+pub fn write_wg_conf_file(
+    ctx: &Context,
+    kind: &str,
+    out_dir: &str,
+    network_path: &str,
+    priv_id_path: &str,
+    exit_node: Option<String>,
+) {
     let priv_id = id::read_id_file(priv_id_path).expect("reading public_id.json issue");
-    let network = network::read_network_file(&network_path).expect("network reading problem");
-    let phonebook = phonebook::curl_phonebook_list(&network.public_endpoints, 3)
-        .expect("couldn't curl phonebook");
+    let network = network::read_network_file(ctx, network_path).expect("network reading problem");
+    if let Some(exit_node) = &exit_node {
+        network
+            .public_endpoint(exit_node)
+            .expect("exit node problem");
+    }
+    let phonebook =
+        phonebook::curl_phonebook_list(ctx, &network, 3).expect("couldn't curl phonebook");
+    let revocations =
+        revocation::curl_revocations_list(ctx, &network, 3).expect("couldn't curl revocations");
+    let phonebook = revocation::without_revoked(ctx, phonebook, &revocations);
+    phonebook::check_routes(&network, &phonebook).expect("phonebook routes problem");
     let name = priv_id.name.clone();
     match phonebook.get(&name) {
         Some(user) => {
-            let mut net_conf =
-                network::read_network_file(&network_path).expect("reading network file problem");
+            let mut net_conf = network::read_network_file(ctx, network_path)
+                .expect("reading network file problem");
             net_conf.user.name = name.clone();
             net_conf.user.vpn_ip = user.vpn_ip.clone();
             let mut wg_conf = net_conf.wg_conf_section(&NetworkWgConfInput {
                 priv_id,
                 mobile: true,
                 port: network.user.port,
+                exit_node,
             });
             wg_conf.push_str(&phonebook.wg_conf_section(()));
             if kind == "qr" {
@@ -115,17 +471,22 @@ pub fn write_wg_conf_file(kind: &str, out_dir: &str, network_path: &str, priv_id
                 .expect("qr code issues");
                 let out_path_aux = Path::new("/tmp").join(format!("{}_tulip_network.svg", &name));
                 let out_path = out_path_aux.to_str().expect("path concat issue");
-                let out_file = create_private_file(&out_path).expect("couldn't make out file");
+                let out_file = create_private_file(out_path).expect("couldn't make out file");
                 write!(&out_file, "{}", &qr).expect("couldn't write the network file");
-                println!("opening {} with your default SVG viewer", &out_path);
-                exec_silent("xdg-open", [&out_path]).expect("couldn't open the svg");
+                ctx.report.written(out_path);
+                ctx.report.say(&format!(
+                    "opening {} with your default SVG viewer",
+                    &out_path
+                ));
+                exec_silent(ctx, "xdg-open", [&out_path]).expect("couldn't open the svg");
             } else {
                 let out_path_aux =
                     Path::new(&out_dir).join(format!("{}_tulip_network.conf", &name));
                 let out_path = out_path_aux.to_str().expect("path concat issue");
-                let out_file = create_private_file(&out_path).expect("couldn't make out file");
+                let out_file = create_private_file(out_path).expect("couldn't make out file");
                 write!(&out_file, "{}", &wg_conf).expect("couldn't write the network file");
-                println!("wrote to {}", &out_path);
+                ctx.report.written(out_path);
+                ctx.report.say(&format!("wrote to {}", &out_path));
             }
         }
         None => {
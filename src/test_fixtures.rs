@@ -0,0 +1,94 @@
+use crate::network::{phonebook::Phonebook, Network, PrivateEndpoint};
+use serde_json::{json, Value};
+
+/*
+ * Networks and phonebooks for the unit tests
+ * The sandringham network (10.0.0.0/16) has two hubs, "hub" (10.0.0.1, the
+ * primary, reached at 192.0.2.1:51820) and "hub2" (10.0.0.2, at
+ * 192.0.2.2:51820), and is seen from the primary hub. Its phonebook has:
+ * - janie (10.0.0.4), in ops
+ * - bob (10.0.0.5), in web and guests, routing 192.168.5.0/24 and fd00:5::/64
+ * - carl (10.0.0.6), in guests
+ * - dora (10.0.0.7), in no group
+ * Their keys are key(<initial>), and the hubs' key('H') and key('I').
+ */
+
+/*
+ * A well-formed WireGuard public key, only good for telling peers apart
+ */
+pub fn key(seed: char) -> String {
+    format!("{}A=", seed.to_string().repeat(42))
+}
+
+fn hub(name: &str, vpn_ip: &str, public_hostname: &str, seed: char) -> Value {
+    json!({
+        "name": name,
+        "vpn_ip": vpn_ip,
+        "public_hostname": public_hostname,
+        "public_key": key(seed),
+        "port": 51820
+    })
+}
+
+/*
+ * The sandringham network, with the fields of `overrides` (e.g. a policy)
+ * replacing its own
+ */
+pub fn network(overrides: Value) -> Network {
+    let mut network = json!({
+        "name": "sandringham",
+        "subnet": "10.0.0.0/16",
+        "user": { "name": "hub", "vpn_ip": "10.0.0.1", "port": 51820 },
+        "public_endpoints": [
+            hub("hub", "10.0.0.1", "192.0.2.1", 'H'),
+            hub("hub2", "10.0.0.2", "192.0.2.2", 'I'),
+        ]
+    });
+    if let (Some(network), Some(overrides)) = (network.as_object_mut(), overrides.as_object()) {
+        network.extend(overrides.clone());
+    }
+    serde_json::from_value(network).expect("test network issue")
+}
+
+/*
+ * A user named `name` at `vpn_ip`, with the fields of `overrides` (e.g. its
+ * routes or groups)
+ */
+pub fn user(name: &str, vpn_ip: &str, overrides: Value) -> PrivateEndpoint {
+    let initial = name.chars().next().unwrap_or('X').to_ascii_uppercase();
+    let mut user = json!({
+        "name": name,
+        "vpn_ip": vpn_ip,
+        "public_key": key(initial),
+    });
+    if let (Some(user), Some(overrides)) = (user.as_object_mut(), overrides.as_object()) {
+        user.extend(overrides.clone());
+    }
+    serde_json::from_value(user).expect("test user issue")
+}
+
+pub fn phonebook_of(users: Vec<PrivateEndpoint>) -> Phonebook {
+    users
+        .into_iter()
+        .map(|user| (user.name.clone(), user))
+        .collect()
+}
+
+/*
+ * The sandringham phonebook
+ */
+pub fn phonebook() -> Phonebook {
+    phonebook_of(vec![
+        user("janie", "10.0.0.4", json!({ "groups": ["ops"] })),
+        user(
+            "bob",
+            "10.0.0.5",
+            json!({
+                "routes": ["192.168.5.0/24", "fd00:5::/64"],
+                "groups": ["web", "guests"]
+            }),
+        ),
+        user("carl", "10.0.0.6", json!({ "groups": ["guests"] })),
+        user("dora", "10.0.0.7", json!({})),
+    ])
+}